@@ -0,0 +1,37 @@
+//! Build info for `/version`.
+use std::{env, process::Command};
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn main() {
+    let commit =
+        output("git", &["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=AGENT_WEB_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=AGENT_WEB_RUSTC={rustc_version}");
+    println!(
+        "cargo:rustc-env=AGENT_WEB_TARGET={}",
+        env::var("TARGET").unwrap()
+    );
+    println!(
+        "cargo:rustc-env=AGENT_WEB_PROFILE={}",
+        env::var("PROFILE").unwrap()
+    );
+    // the commit moves with HEAD and the branch it points to
+    if let Some(git_dir) = output("git", &["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        if let Some(branch) = output("git", &["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={git_dir}/{branch}");
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,4 @@
+-- Full-text index over chat messages, queries must use the same expression.
+
+CREATE INDEX IF NOT EXISTS idx_chat_history_fts
+ON chat_history USING GIN (to_tsvector('simple', COALESCE(message, '')));
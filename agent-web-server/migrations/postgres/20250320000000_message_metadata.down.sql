@@ -0,0 +1,4 @@
+ALTER TABLE chat_history DROP COLUMN IF EXISTS status;
+ALTER TABLE chat_history DROP COLUMN IF EXISTS finish_reason;
+ALTER TABLE chat_history DROP COLUMN IF EXISTS latency_ms;
+ALTER TABLE chat_history DROP COLUMN IF EXISTS model;
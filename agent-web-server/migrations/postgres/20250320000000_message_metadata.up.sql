@@ -0,0 +1,6 @@
+-- Metadata of the completion producing a message, NULL for user messages.
+
+ALTER TABLE chat_history ADD COLUMN IF NOT EXISTS model TEXT;
+ALTER TABLE chat_history ADD COLUMN IF NOT EXISTS latency_ms BIGINT;
+ALTER TABLE chat_history ADD COLUMN IF NOT EXISTS finish_reason TEXT;
+ALTER TABLE chat_history ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'ok';
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS purge_run;
+DROP TABLE IF EXISTS pinned_conversation;
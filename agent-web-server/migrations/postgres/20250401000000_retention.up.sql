@@ -0,0 +1,15 @@
+-- Conversations exempt from retention, and an audit trail of purges.
+
+CREATE TABLE IF NOT EXISTS pinned_conversation (
+    uuid TEXT PRIMARY KEY,
+    pinned_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC')
+);
+
+CREATE TABLE IF NOT EXISTS purge_run (
+    id BIGSERIAL PRIMARY KEY,
+    time TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+    source TEXT NOT NULL,
+    conversations BIGINT NOT NULL,
+    messages BIGINT NOT NULL,
+    pinned_kept BIGINT NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Cleared history goes to the trash first, restorable until retention purges it.
+
+ALTER TABLE chat_history ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;
+CREATE INDEX IF NOT EXISTS idx_chat_history_deleted_at ON chat_history (deleted_at);
+
+ALTER TABLE purge_run ADD COLUMN IF NOT EXISTS trash_messages BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Disabled users keep their history but can no longer authenticate.
+
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS disabled_at TIMESTAMP;
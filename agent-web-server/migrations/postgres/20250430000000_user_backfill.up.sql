@@ -0,0 +1,3 @@
+-- Sessions from before users existed would otherwise be rejected as unknown.
+
+INSERT INTO "user" (uuid) SELECT DISTINCT uuid FROM chat_history ON CONFLICT DO NOTHING;
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS token_usage;
+DROP TABLE IF EXISTS rate_limit_bucket;
+DROP TABLE IF EXISTS user;
+DROP TABLE IF EXISTS api_key;
+DROP TABLE IF EXISTS chat_history;
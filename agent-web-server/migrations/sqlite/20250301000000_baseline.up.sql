@@ -0,0 +1,49 @@
+-- Schema as of the last release without versioned migrations.
+-- Every statement tolerates objects created by the former ad-hoc setup.
+
+CREATE TABLE IF NOT EXISTS chat_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT NOT NULL,
+    message TEXT,
+    role TEXT NOT NULL,
+    time DATETIME DEFAULT CURRENT_TIMESTAMP,
+    prompt_tokens INTEGER NOT NULL DEFAULT 0,
+    completion_tokens INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS idx_user_created_at ON chat_history (uuid, time);
+
+CREATE TABLE IF NOT EXISTS api_key (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT NOT NULL,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    prefix TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME,
+    last_used_ip TEXT,
+    revoked_at DATETIME
+);
+CREATE INDEX IF NOT EXISTS idx_api_key_uuid ON api_key (uuid);
+
+CREATE TABLE IF NOT EXISTS user (
+    uuid TEXT PRIMARY KEY,
+    role TEXT NOT NULL DEFAULT 'user',
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS rate_limit_bucket (
+    key TEXT PRIMARY KEY,
+    tokens REAL NOT NULL,
+    updated_at REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS token_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    time DATETIME DEFAULT CURRENT_TIMESTAMP,
+    cost REAL NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS idx_token_usage_uuid_time ON token_usage (uuid, time);
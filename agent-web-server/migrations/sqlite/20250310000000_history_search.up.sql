@@ -0,0 +1,23 @@
+-- Full-text index over chat messages, kept in sync by triggers.
+
+CREATE VIRTUAL TABLE chat_history_fts USING fts5(
+    message,
+    content = 'chat_history',
+    content_rowid = 'id'
+);
+INSERT INTO chat_history_fts (chat_history_fts) VALUES ('rebuild');
+
+CREATE TRIGGER chat_history_fts_insert AFTER INSERT ON chat_history BEGIN
+    INSERT INTO chat_history_fts (rowid, message) VALUES (new.id, new.message);
+END;
+
+CREATE TRIGGER chat_history_fts_delete AFTER DELETE ON chat_history BEGIN
+    INSERT INTO chat_history_fts (chat_history_fts, rowid, message)
+    VALUES ('delete', old.id, old.message);
+END;
+
+CREATE TRIGGER chat_history_fts_update AFTER UPDATE OF message ON chat_history BEGIN
+    INSERT INTO chat_history_fts (chat_history_fts, rowid, message)
+    VALUES ('delete', old.id, old.message);
+    INSERT INTO chat_history_fts (rowid, message) VALUES (new.id, new.message);
+END;
@@ -0,0 +1,4 @@
+ALTER TABLE chat_history DROP COLUMN status;
+ALTER TABLE chat_history DROP COLUMN finish_reason;
+ALTER TABLE chat_history DROP COLUMN latency_ms;
+ALTER TABLE chat_history DROP COLUMN model;
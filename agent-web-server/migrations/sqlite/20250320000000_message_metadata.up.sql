@@ -0,0 +1,6 @@
+-- Metadata of the completion producing a message, NULL for user messages.
+
+ALTER TABLE chat_history ADD COLUMN model TEXT;
+ALTER TABLE chat_history ADD COLUMN latency_ms INTEGER;
+ALTER TABLE chat_history ADD COLUMN finish_reason TEXT;
+ALTER TABLE chat_history ADD COLUMN status TEXT NOT NULL DEFAULT 'ok';
@@ -0,0 +1,15 @@
+-- Conversations exempt from retention, and an audit trail of purges.
+
+CREATE TABLE IF NOT EXISTS pinned_conversation (
+    uuid TEXT PRIMARY KEY,
+    pinned_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS purge_run (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    time DATETIME DEFAULT CURRENT_TIMESTAMP,
+    source TEXT NOT NULL,
+    conversations INTEGER NOT NULL,
+    messages INTEGER NOT NULL,
+    pinned_kept INTEGER NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Trashed messages would reappear as live history.
+DELETE FROM chat_history WHERE deleted_at IS NOT NULL;
+DROP INDEX IF EXISTS idx_chat_history_deleted_at;
+ALTER TABLE chat_history DROP COLUMN deleted_at;
+
+ALTER TABLE purge_run DROP COLUMN trash_messages;
@@ -0,0 +1 @@
+-- Backfilled users can't be told apart from registered ones, so they stay.
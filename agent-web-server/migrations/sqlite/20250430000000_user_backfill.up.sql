@@ -0,0 +1,4 @@
+-- Sessions from before users existed would otherwise be rejected as unknown.
+-- WHERE keeps SQLite from reading ON CONFLICT as a join constraint.
+
+INSERT INTO user (uuid) SELECT DISTINCT uuid FROM chat_history WHERE true ON CONFLICT DO NOTHING;
@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Instant};
 
 use anyhow::{Result, anyhow};
 use async_openai::types::{
@@ -6,21 +6,49 @@ use async_openai::types::{
     ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
     CreateChatCompletionRequestArgs,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 use strum::{Display, EnumString};
+use time::{format_description::BorrowedFormatItem, macros::format_description};
+use tracing::Instrument;
 
 use crate::{
     indoc_info,
-    states::{AGENT_CLIENT, SERVER_CONFIG},
+    metrics::METRICS,
+    states::{agent_client, server_config},
+    telemetry,
 };
 
+/// Tokens consumed by one completion.
+#[derive(FromRow, Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct TokenUsage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> i64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Answer of the first choice together with its cost.
+#[derive(Debug)]
+pub struct AgentReply {
+    pub content: String,
+    pub usage: TokenUsage,
+    /// Model reported by the API, may be more specific than the one requested.
+    pub model: String,
+    pub latency_ms: i64,
+    pub finish_reason: Option<String>,
+}
+
 /// Fire messages to API, returns raw answer (first choice).
-pub async fn send_request<I>(messages: I) -> Result<String>
+pub async fn send_request<I>(messages: I) -> Result<AgentReply>
 where
     I: IntoIterator<Item = ChatMessage>,
 {
-    let server_config = SERVER_CONFIG.get().unwrap();
+    let server_config = server_config();
     let sys_message = ChatCompletionRequestSystemMessageArgs::default()
         .content(server_config.sys_prompt.clone())
         .build()?
@@ -28,6 +56,8 @@ where
 
     use MessageRole::*;
     let messages = messages.into_iter().filter_map(|m| match m.get_role() {
+        // failed attempts carry no content
+        _ if m.get_status() != MessageStatus::Ok => None,
         User => ChatCompletionRequestUserMessageArgs::default()
             .content(m.content)
             .build()
@@ -49,11 +79,26 @@ where
         .messages(complete_messages)
         .build()?;
 
-    let client = AGENT_CLIENT.get().unwrap();
-    let response = client.chat().create(request).await?;
-    if let Some(ref usage) = response.usage {
-        indoc_info!("consumed {} tokens", usage.total_tokens);
-    }
+    let client = agent_client();
+    let span = telemetry::llm_span(&server_config.model);
+    let started = Instant::now();
+    let response = client.chat().create(request).instrument(span.clone()).await;
+    METRICS.observe_llm(&server_config.model, started.elapsed(), &response);
+    telemetry::record_outcome(&span, &response);
+    let response = response?;
+    let latency_ms = started.elapsed().as_millis() as i64;
+    let usage = match response.usage {
+        Some(ref usage) => {
+            indoc_info!("consumed {} tokens", usage.total_tokens);
+            TokenUsage {
+                prompt_tokens: usage.prompt_tokens.into(),
+                completion_tokens: usage.completion_tokens.into(),
+            }
+        }
+        None => TokenUsage::default(),
+    };
+    METRICS.add_tokens(&server_config.model, &usage);
+    telemetry::record_tokens(&span, &usage);
     let Some(reply) = response.choices.first() else {
         let resp_json = serde_json::to_string_pretty(&response)
             .unwrap_or("cannot parse response to json".into());
@@ -66,7 +111,18 @@ where
             reason
         ));
     };
-    Ok(content.clone())
+    // serialized the same as the API, e.g. "content_filter"
+    let finish_reason = reply
+        .finish_reason
+        .and_then(|r| serde_json::to_value(r).ok())
+        .and_then(|v| v.as_str().map(str::to_string));
+    Ok(AgentReply {
+        content: content.clone(),
+        usage,
+        model: response.model.clone(),
+        latency_ms,
+        finish_reason,
+    })
 }
 
 #[derive(EnumString, Display)]
@@ -75,14 +131,57 @@ pub enum MessageRole {
     Assistant,
 }
 
-#[derive(FromRow, Debug, Serialize)]
+/// Outcome of the request producing a message.
+#[derive(EnumString, Display, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum MessageStatus {
+    Ok,
+    /// The API failed, content is empty.
+    Error,
+    /// The client went away before the answer, content is empty.
+    Cancelled,
+    /// The server shut down before the answer, content is empty.
+    Interrupted,
+}
+
+/// Format of [ChatMessage::time], naive UTC.
+pub const TIME_FORMAT: &[BorrowedFormatItem] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+
+#[derive(FromRow, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChatMessage {
-    #[serde(skip_serializing)]
+    /// Row id, 0 until persisted, doubles as pagination cursor.
+    #[serde(default)]
+    pub id: i64,
+    #[serde(skip_serializing, default)]
     pub uuid: String,
     #[sqlx(rename = "message")]
     pub content: String,
     // sqlx does not support deserialize to enum
     pub role: String,
+    /// Usage of the completion producing this message, zero for user messages.
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub usage: TokenUsage,
+    /// Model answering, `None` for user messages.
+    pub model: Option<String>,
+    pub latency_ms: Option<i64>,
+    pub finish_reason: Option<String>,
+    /// See [MessageStatus].
+    pub status: String,
+    /// UTC `YYYY-MM-DD HH:MM:SS`, empty until persisted.
+    pub time: String,
+}
+
+/// Position in a chat history, by message id.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryCursor {
+    /// The most recent messages.
+    Latest,
+    /// Messages older than the id.
+    Before(i64),
+    /// Messages newer than the id.
+    After(i64),
 }
 
 impl ChatMessage {
@@ -90,15 +189,38 @@ impl ChatMessage {
         Self::create(uuid, content, MessageRole::User)
     }
 
-    pub fn create_assistant(uuid: &str, content: &str) -> Self {
-        Self::create(uuid, content, MessageRole::Assistant)
+    pub fn create_assistant(uuid: &str, reply: &AgentReply) -> Self {
+        Self {
+            usage: reply.usage,
+            model: Some(reply.model.clone()),
+            latency_ms: Some(reply.latency_ms),
+            finish_reason: reply.finish_reason.clone(),
+            ..Self::create(uuid, &reply.content, MessageRole::Assistant)
+        }
+    }
+
+    /// Placeholder of an answer that never arrived.
+    pub fn create_failed(uuid: &str, model: &str, latency_ms: i64, status: MessageStatus) -> Self {
+        Self {
+            model: Some(model.to_string()),
+            latency_ms: Some(latency_ms),
+            status: status.to_string(),
+            ..Self::create(uuid, "", MessageRole::Assistant)
+        }
     }
 
     fn create(uuid: &str, content: &str, role: MessageRole) -> Self {
         Self {
+            id: 0,
             uuid: uuid.to_string(),
             content: content.to_string(),
             role: role.to_string(),
+            usage: TokenUsage::default(),
+            model: None,
+            latency_ms: None,
+            finish_reason: None,
+            status: MessageStatus::Ok.to_string(),
+            time: String::new(),
         }
     }
 
@@ -110,6 +232,15 @@ impl ChatMessage {
             }
         }
     }
+
+    pub fn get_status(&self) -> MessageStatus {
+        match MessageStatus::from_str(&self.status) {
+            Ok(s) => s,
+            Err(_) => {
+                unreachable!()
+            }
+        }
+    }
 }
 
 #[allow(unused)]
@@ -118,7 +249,18 @@ mod test {
 
     #[test]
     fn enum_convert_string() {
-        let msg = ChatMessage::create_assistant("abc", "def");
+        let reply = AgentReply {
+            content: "def".into(),
+            usage: TokenUsage::default(),
+            model: "gpt".into(),
+            latency_ms: 10,
+            finish_reason: Some("stop".into()),
+        };
+        let msg = ChatMessage::create_assistant("abc", &reply);
         println!("{:?}", msg);
+        assert_eq!(msg.get_status(), MessageStatus::Ok);
+        let msg = ChatMessage::create_failed("abc", "gpt", 10, MessageStatus::Cancelled);
+        assert_eq!(msg.status, "cancelled");
+        assert_eq!(msg.get_status(), MessageStatus::Cancelled);
     }
 }
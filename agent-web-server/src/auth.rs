@@ -3,21 +3,79 @@ use std::{
     fs::{File, OpenOptions},
     io::{Read, Write},
     net::SocketAddr,
+    str::FromStr,
 };
 
-use anyhow::{Context, anyhow};
-use axum::{Json, extract::FromRequest, http::StatusCode};
+use anyhow::{Context, anyhow, bail};
+use axum::{
+    Json,
+    extract::{FromRequest, Request},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
 use jwt_simple::prelude::*;
 use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use sqlx::prelude::FromRow;
+use strum::{Display, EnumString};
 
 use crate::{
-    indoc_info,
-    states::{DATA_DIR, JWT_KEY, SERVER_CONFIG},
+    UserAction, indoc_info, indoc_warn,
+    listen::ClientAddr,
+    states::{DATA_DIR, JWT_KEY, server_config},
+    store, telemetry,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Every API key starts with this, so it can be told apart from a JWT.
+pub const API_KEY_PREFIX: &str = "awk_";
+
+/// Ordered by privilege, a higher role can do whatever a lower role can.
+#[derive(
+    EnumString,
+    Display,
+    Serialize,
+    Deserialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JwtClaim {
     pub uuid: String,
+    // tokens issued before roles existed carry none
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(FromRow, Debug, Serialize)]
+pub struct User {
+    pub uuid: String,
+    // sqlx does not support deserialize to enum
+    pub role: String,
+    pub created_at: String,
+    /// Disabled users can not authenticate.
+    pub disabled_at: Option<String>,
+}
+
+impl User {
+    pub fn get_role(&self) -> Role {
+        Role::from_str(&self.role).unwrap_or_default()
+    }
 }
 
 pub fn init_jwt_key() -> anyhow::Result<Vec<u8>> {
@@ -66,7 +124,7 @@ pub fn init_jwt_key() -> anyhow::Result<Vec<u8>> {
 }
 
 pub fn gen_jwt(custom_claim: JwtClaim) -> String {
-    let config = SERVER_CONFIG.get().unwrap();
+    let config = server_config();
     let key = JWT_KEY.get().unwrap();
     let claim =
         Claims::with_custom_claims(custom_claim, Duration::from_days(config.jwt_expire_days));
@@ -86,18 +144,267 @@ pub fn verify_jwt(token: &str) -> anyhow::Result<JwtClaim> {
     Ok(claims.custom)
 }
 
+/// Long-lived credential for programmatic clients.
+///
+/// Only the SHA-256 hash of the key is stored,
+/// the plain key is shown once on creation.
+#[derive(FromRow, Debug, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub uuid: String,
+    pub name: String,
+    /// First characters of the plain key, for the user to recognize it.
+    pub prefix: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub last_used_ip: Option<String>,
+}
+
+/// Generate a new plain API key, 256 random bits in hex.
+pub fn gen_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{API_KEY_PREFIX}{hex}")
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Resolve an API key to the claim of its owner, recording the usage.
+pub async fn verify_api_key(key: &str, ip: Option<SocketAddr>) -> anyhow::Result<JwtClaim> {
+    let key_hash = hash_api_key(key);
+    let api_key = ApiKey::find_active(&key_hash)
+        .await
+        .ok_or(anyhow!("invalid api key"))?;
+    ApiKey::touch(api_key.id, ip).await;
+    // keys live long, take the current role rather than the one at creation
+    let role = User::find(&api_key.uuid)
+        .await
+        .map(|u| u.get_role())
+        .unwrap_or_default();
+    Ok(JwtClaim {
+        uuid: api_key.uuid,
+        role,
+    })
+}
+
+/// Make sure at least one admin exists.
+///
+/// If there is none, create one and print its token to stdout, kept out of the logs,
+/// which can then be exchanged for a long-lived API key.
+pub async fn init_admin() -> anyhow::Result<()> {
+    let repo = store::repo();
+    // an error must not pass for "no admin", or each failure would create another
+    if repo.count_users_by_role(Role::Admin).await? > 0 {
+        return Ok(());
+    }
+    let uuid = uuid::Uuid::new_v4().to_string();
+    repo.create_user(&uuid, Role::Admin).await?;
+    let jwt = gen_jwt(JwtClaim {
+        uuid: uuid.clone(),
+        role: Role::Admin,
+    });
+    indoc_info!("Admin {uuid} created, token printed to stdout.");
+    println!("Newly created admin token:\n{jwt}");
+    Ok(())
+}
+
+/// Minimum role of each route, `None` for public routes.
+///
+/// Routes missing from the table are admin only.
+pub const ROUTE_ROLES: &[(&str, Option<Role>)] = &[
+    ("/init-session", None),
+    ("/fetch-history", Some(Role::User)),
+    ("/clear-history", Some(Role::User)),
+    ("/restore-history", Some(Role::User)),
+    ("/ask-agent", Some(Role::User)),
+    ("/search-history", Some(Role::User)),
+    ("/export-history", Some(Role::User)),
+    ("/import-history", Some(Role::User)),
+    ("/usage", Some(Role::User)),
+    ("/create-api-key", Some(Role::User)),
+    ("/list-api-keys", Some(Role::User)),
+    ("/revoke-api-key", Some(Role::User)),
+    ("/test-auth", Some(Role::User)),
+    ("/admin/list-users", Some(Role::Admin)),
+    ("/admin/fetch-history", Some(Role::Admin)),
+    ("/admin/clear-history", Some(Role::Admin)),
+    ("/admin/restore-history", Some(Role::Admin)),
+    ("/admin/export-history", Some(Role::Admin)),
+    ("/admin/cost", Some(Role::Admin)),
+    ("/admin/backup", Some(Role::Admin)),
+    ("/admin/purge", Some(Role::Admin)),
+    ("/admin/purge-runs", Some(Role::Admin)),
+    ("/admin/pin-history", Some(Role::Admin)),
+    ("/healthz", None),
+    ("/readyz", None),
+    ("/version", None),
+    ("/metrics", None),
+];
+
+pub fn required_role(path: &str) -> Option<Role> {
+    ROUTE_ROLES
+        .iter()
+        .find(|(route, _)| *route == path)
+        .map_or(Some(Role::Admin), |(_, role)| *role)
+}
+
+/// Middleware authenticating the caller and checking its role against [ROUTE_ROLES].
+///
+/// The verified [JwtClaim] is put into request extensions for [AuthReq].
+pub async fn enforce_role(mut req: Request, next: Next) -> Result<Response, (StatusCode, String)> {
+    let Some(role) = required_role(req.uri().path()) else {
+        return Ok(next.run(req).await);
+    };
+    let claim = authenticate(req.headers(), client_ip(&req)).await?;
+    telemetry::record_user(&claim.uuid);
+    // tokens stay valid until expiry, so the user is checked on every call
+    let user = store::repo().find_user(&claim.uuid).await.map_err(|e| {
+        indoc_warn!(
+            "
+            Query user failed, error:
+            {e:#}
+            "
+        );
+        (
+            // can't tell whether the user is disabled
+            StatusCode::SERVICE_UNAVAILABLE,
+            "User lookup failed, try again later.".to_string(),
+        )
+    })?;
+    let Some(user) = user else {
+        return Err((
+            // token of a user not in the database
+            StatusCode::UNAUTHORIZED,
+            "Unknown user.".to_string(),
+        ));
+    };
+    if user.disabled_at.is_some() {
+        return Err((
+            // user disabled by an operator
+            StatusCode::FORBIDDEN,
+            "User disabled.".to_string(),
+        ));
+    }
+    // the stored role wins, so that a role change applies before the token expires
+    let claim = JwtClaim {
+        role: user.get_role(),
+        ..claim
+    };
+    if claim.role < role {
+        return Err((
+            // authenticated but not privileged enough
+            StatusCode::FORBIDDEN,
+            format!("Requires role {role}."),
+        ));
+    }
+    req.extensions_mut().insert(claim);
+    Ok(next.run(req).await)
+}
+
+fn client_ip(req: &Request) -> Option<SocketAddr> {
+    req.extensions()
+        .get::<axum::extract::ConnectInfo<ClientAddr>>()
+        .and_then(|ci| ci.0.0)
+}
+
+/// Verify the bearer credential, either a JWT or an API key.
+async fn authenticate(
+    headers: &HeaderMap,
+    ip: Option<SocketAddr>,
+) -> Result<JwtClaim, (StatusCode, String)> {
+    let auth_header = headers.get("authorization").ok_or((
+        // no authorization header key
+        StatusCode::UNAUTHORIZED,
+        "Missing authorization header.".to_string(),
+    ))?;
+    let auth_str = auth_header.to_str().map_err(|_| {
+        (
+            // authorization value not a valid string
+            StatusCode::BAD_REQUEST,
+            "Invalid authorization header.".to_string(),
+        )
+    })?;
+    if !auth_str.starts_with("Bearer ") {
+        return Err((
+            // authorization value without specifying Bearer
+            StatusCode::BAD_REQUEST,
+            "Invalid authorization scheme.".to_string(),
+        ));
+    }
+    let token = auth_str.trim_start_matches("Bearer ").trim();
+    if token.starts_with(API_KEY_PREFIX) {
+        verify_api_key(token, ip).await.map_err(|_| {
+            (
+                // unknown or revoked api key
+                StatusCode::UNAUTHORIZED,
+                "Invalid or revoked API key.".to_string(),
+            )
+        })
+    } else {
+        verify_jwt(token).map_err(|_| {
+            (
+                // invalid jwt
+                StatusCode::UNAUTHORIZED,
+                "Invalid or expired JWT.".to_string(),
+            )
+        })
+    }
+}
+
+/// Run the `user` subcommand.
+pub async fn user_command(action: UserAction) -> anyhow::Result<()> {
+    let repo = store::repo();
+    match action {
+        UserAction::Add { role } => {
+            let uuid = uuid::Uuid::new_v4().to_string();
+            repo.create_user(&uuid, role).await?;
+            let jwt = gen_jwt(JwtClaim {
+                uuid: uuid.clone(),
+                role,
+            });
+            println!("uuid:  {uuid}\nrole:  {role}\ntoken: {jwt}");
+        }
+        UserAction::List => {
+            println!("uuid\trole\tcreated_at\tdisabled_at");
+            for user in repo.list_users().await? {
+                let disabled_at = user.disabled_at.as_deref().unwrap_or("-");
+                println!(
+                    "{}\t{}\t{}\t{disabled_at}",
+                    user.uuid, user.role, user.created_at
+                );
+            }
+        }
+        UserAction::Disable { ref uuid } | UserAction::Enable { ref uuid } => {
+            let disabled = matches!(action, UserAction::Disable { .. });
+            if !repo.set_user_disabled(uuid, disabled).await? {
+                bail!("no user {uuid}");
+            }
+            let state = if disabled { "disabled" } else { "enabled" };
+            println!("user {uuid} {state}");
+        }
+    }
+    Ok(())
+}
+
 #[allow(unused)]
 mod test {
     use super::*;
 
     #[test]
     fn test_jwt() {
-        let key = HS256Key::generate();
+        // shared with the middleware test
+        let key = JWT_KEY.get_or_init(HS256Key::generate).clone();
         let key_str = String::from_utf8_lossy(&key.to_bytes()).to_string();
         println!("key: {key_str}");
-        JWT_KEY.set(key.clone()).unwrap();
         let uuid = uuid::Uuid::new_v4().to_string();
-        let custom_claim = JwtClaim { uuid };
+        let custom_claim = JwtClaim {
+            uuid,
+            role: Role::User,
+        };
         let claim = Claims::with_custom_claims(custom_claim, Duration::from_hours(2));
         let mut token = key.authenticate(claim).unwrap();
         println!("{token}");
@@ -108,6 +415,161 @@ mod test {
         let res = verify_jwt(&token);
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_api_key() {
+        let key = gen_api_key();
+        assert!(key.starts_with(API_KEY_PREFIX));
+        assert_eq!(key.len(), API_KEY_PREFIX.len() + 64);
+        assert_ne!(key, gen_api_key());
+        let hash = hash_api_key(&key);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_api_key(&key));
+        assert_ne!(hash, hash_api_key(&gen_api_key()));
+    }
+
+    #[test]
+    fn test_route_roles() {
+        let table = [
+            ("/init-session", None),
+            ("/fetch-history", Some(Role::User)),
+            ("/ask-agent", Some(Role::User)),
+            ("/search-history", Some(Role::User)),
+            ("/export-history", Some(Role::User)),
+            ("/import-history", Some(Role::User)),
+            ("/create-api-key", Some(Role::User)),
+            ("/admin/list-users", Some(Role::Admin)),
+            ("/admin/fetch-history", Some(Role::Admin)),
+            ("/admin/clear-history", Some(Role::Admin)),
+            ("/readyz", None),
+            ("/admin/unknown", Some(Role::Admin)),
+            ("/unknown", Some(Role::Admin)),
+        ];
+        for (path, role) in table {
+            assert_eq!(required_role(path), role, "{path}");
+        }
+        assert!(Role::Admin > Role::User);
+    }
+
+    /// Disabling and role changes apply to tokens and keys issued before.
+    #[tokio::test]
+    async fn test_enforce_role() {
+        use axum::{Router, middleware, routing::post};
+        use tokio::net::TcpListener;
+
+        use crate::{
+            config::ServerConfig, listen::serve_on, states::SERVER_CONFIG,
+            store::test::global_repository,
+        };
+
+        JWT_KEY.get_or_init(HS256Key::generate);
+        SERVER_CONFIG.get_or_init(|| arc_swap::ArcSwap::from_pointee(ServerConfig::default()));
+        let repo = global_repository().await;
+
+        let app = Router::new()
+            .route("/test-auth", post(|| async { "ok" }))
+            .route("/admin/list-users", post(|| async { "ok" }))
+            .route_layer(middleware::from_fn(enforce_role));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener, app));
+        let client = reqwest::Client::new();
+        let status = |path: &'static str, token: String| {
+            let request = client
+                .post(format!("http://{addr}{path}"))
+                .bearer_auth(token)
+                .send();
+            async move { request.await.unwrap().status() }
+        };
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        repo.create_user(&uuid, Role::User).await.unwrap();
+        let jwt = gen_jwt(JwtClaim {
+            uuid: uuid.clone(),
+            role: Role::User,
+        });
+        let api_key = gen_api_key();
+        repo.create_api_key(&uuid, "ci", &hash_api_key(&api_key), "awk_")
+            .await
+            .unwrap();
+        assert_eq!(status("/test-auth", jwt.clone()).await, StatusCode::OK);
+        assert_eq!(status("/test-auth", api_key.clone()).await, StatusCode::OK);
+
+        repo.set_user_disabled(&uuid, true).await.unwrap();
+        assert_eq!(status("/test-auth", jwt).await, StatusCode::FORBIDDEN);
+        assert_eq!(status("/test-auth", api_key).await, StatusCode::FORBIDDEN);
+
+        // a token still claiming admin after the user was demoted
+        let demoted = uuid::Uuid::new_v4().to_string();
+        repo.create_user(&demoted, Role::User).await.unwrap();
+        let jwt = gen_jwt(JwtClaim {
+            uuid: demoted,
+            role: Role::Admin,
+        });
+        assert_eq!(status("/test-auth", jwt.clone()).await, StatusCode::OK);
+        assert_eq!(
+            status("/admin/list-users", jwt).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let unknown = gen_jwt(JwtClaim {
+            uuid: uuid::Uuid::new_v4().to_string(),
+            role: Role::Admin,
+        });
+        assert_eq!(
+            status("/test-auth", unknown).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    /// A token issued before users existed still reaches its history after the upgrade.
+    #[tokio::test]
+    async fn test_legacy_session() {
+        use axum::{Router, middleware, routing::post};
+        use tokio::net::TcpListener;
+
+        use crate::{
+            config::ServerConfig,
+            controller::fetch_history,
+            listen::serve_on,
+            states::SERVER_CONFIG,
+            store::test::{LEGACY_UUID, global_repository},
+        };
+
+        let key = JWT_KEY.get_or_init(HS256Key::generate);
+        SERVER_CONFIG.get_or_init(|| arc_swap::ArcSwap::from_pointee(ServerConfig::default()));
+        global_repository().await;
+
+        let app = Router::new()
+            .route("/fetch-history", post(fetch_history))
+            .route_layer(middleware::from_fn(enforce_role));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener, app));
+
+        // claims of that time carry no role
+        let claim = serde_json::json!({ "uuid": LEGACY_UUID });
+        let claim = Claims::with_custom_claims(claim, Duration::from_hours(2));
+        let token = key.authenticate(claim).unwrap();
+        let res = reqwest::Client::new()
+            .post(format!("http://{addr}/fetch-history"))
+            .bearer_auth(token)
+            .json(&serde_json::Value::Null)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["data"][0]["content"], "hi", "{body}");
+    }
+
+    #[test]
+    fn test_claim_without_role() {
+        let claim: JwtClaim = serde_json::from_str(r#"{"uuid":"abc"}"#).unwrap();
+        assert_eq!(claim.role, Role::User);
+        let claim: JwtClaim = serde_json::from_str(r#"{"uuid":"abc","role":"admin"}"#).unwrap();
+        assert_eq!(claim.role, Role::Admin);
+    }
 }
 
 #[derive(Debug)]
@@ -128,37 +590,16 @@ where
     type Rejection = (StatusCode, String);
 
     async fn from_request(req: axum::extract::Request, state: &S) -> Result<Self, Self::Rejection> {
-        let auth_header = req.headers().get("authorization").ok_or((
-            // no authorization header key
-            StatusCode::UNAUTHORIZED,
-            "Missing authorization header.".to_string(),
-        ))?;
-        let auth_str = auth_header.to_str().map_err(|_| {
-            (
-                // authorization value not a valid string
-                StatusCode::BAD_REQUEST,
-                "Invalid authorization header.".to_string(),
-            )
-        })?;
-        if !auth_str.starts_with("Bearer ") {
-            return Err((
-                // authorization value without specifying Bearer
-                StatusCode::BAD_REQUEST,
-                "Invalid authorization scheme.".to_string(),
-            ));
-        }
-        let token = auth_str.trim_start_matches("Bearer ").trim();
-        let claim = verify_jwt(token).map_err(|_| {
-            (
-                // invalid jwt
-                StatusCode::UNAUTHORIZED,
-                "Invalid or expired JWT.".to_string(),
-            )
-        })?;
-        let ip = req
-            .extensions()
-            .get::<axum::extract::ConnectInfo<SocketAddr>>()
-            .map(|ci| ci.0);
+        // already verified by [enforce_role]
+        let ip = client_ip(&req);
+        let claim = match req.extensions().get::<JwtClaim>() {
+            Some(claim) => claim.clone(),
+            None => {
+                let claim = authenticate(req.headers(), ip).await?;
+                telemetry::record_user(&claim.uuid);
+                claim
+            }
+        };
         let Json(body) = Json::<T>::from_request(req, state).await.map_err(|err| {
             (
                 // request body not a valid json
@@ -0,0 +1,501 @@
+/// Online backup of database, config and key files into timestamped `tar.gz` archives,
+/// and restore of such an archive into the data directory.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, format_description::BorrowedFormatItem, macros::format_description};
+
+use crate::{
+    config::{self, ServerConfig},
+    indoc_info, indoc_warn,
+    shutdown::SHUTDOWN,
+    states::{DATA_DIR, server_config},
+    store::{self, Repository, sqlite::SqliteRepository},
+};
+
+/// Bumped on incompatible changes of the archive layout.
+pub const BACKUP_FORMAT: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const DATABASE: &str = "store.db";
+const CONFIG: &str = config::CONFIG_FILE;
+const STAMP_FORMAT: &[BorrowedFormatItem] =
+    format_description!("[year][month][day]-[hour][minute][second]");
+
+/// First entry of every archive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub format: u32,
+    /// Server version that took the backup.
+    pub version: String,
+    /// UTC, `YYYYMMDD-hhmmss`.
+    pub created: String,
+    /// Path relative to data directory -> SHA-256 hex.
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BackupInfo {
+    /// File name in the backup directory.
+    pub file: String,
+    pub size: u64,
+}
+
+fn stamp() -> anyhow::Result<String> {
+    Ok(OffsetDateTime::now_utc().format(STAMP_FORMAT)?)
+}
+
+/// Files of the data directory bundled with the database, if they exist.
+fn bundled_files(config: &ServerConfig) -> Vec<String> {
+    let mut files = vec![CONFIG.to_string(), "jwt_key".to_string()];
+    files.push(config.encryption.key_file.clone());
+    files.extend(config.encryption.previous_key_files.iter().cloned());
+    files
+}
+
+fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Archives bundle the JWT and master keys, only the owner may read them.
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Directory for unpacked or replaced key files, only the owner may enter it.
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+}
+
+/// Relative path without `..`, so that entries can't escape the data directory.
+fn is_plain_relative(name: &str) -> bool {
+    let path = Path::new(name);
+    !name.is_empty() && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Archive name -> source file.
+fn write_archive(dest: &Path, created: &str, files: &[(String, PathBuf)]) -> anyhow::Result<()> {
+    let mut manifest = Manifest {
+        format: BACKUP_FORMAT,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created: created.to_string(),
+        files: BTreeMap::new(),
+    };
+    for (name, src) in files {
+        manifest.files.insert(name.clone(), sha256_file(src)?);
+    }
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+
+    let file = create_private_file(dest).with_context(|| format!("create {}", dest.display()))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(OffsetDateTime::now_utc().unix_timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+    for (name, src) in files {
+        builder
+            .append_path_with_name(src, name)
+            .with_context(|| format!("archive {}", src.display()))?;
+    }
+    builder.into_inner()?.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Snapshot the database while serving and archive it with config and keys.
+pub async fn create(
+    repo: &dyn Repository,
+    config: &ServerConfig,
+    data_dir: &Path,
+) -> anyhow::Result<BackupInfo> {
+    let backup_dir = data_dir.join(&config.backup.dir);
+    fs::create_dir_all(&backup_dir).with_context(|| "create backup directory")?;
+    let created = stamp()?;
+    let name = format!("backup-{created}.tar.gz");
+    let path = backup_dir.join(&name);
+    if path.exists() {
+        bail!("backup {name} already exists");
+    }
+    // dot files are never mistaken for finished backups
+    let staging = backup_dir.join(format!(".staging-{created}"));
+    let partial = backup_dir.join(format!(".{name}.partial"));
+    create_private_dir(&staging).with_context(|| "create staging directory")?;
+
+    let res = async {
+        let snapshot = staging.join(DATABASE);
+        repo.snapshot(&snapshot)
+            .await
+            .with_context(|| "snapshot database")?;
+        let mut files = vec![(DATABASE.to_string(), snapshot)];
+        for file in bundled_files(config) {
+            let src = data_dir.join(&file);
+            if src.is_file() && !files.iter().any(|(name, _)| *name == file) {
+                files.push((file, src));
+            }
+        }
+        let dest = partial.clone();
+        tokio::task::spawn_blocking(move || write_archive(&dest, &created, &files)).await??;
+        fs::rename(&partial, &path).with_context(|| "finish backup")
+    }
+    .await;
+    let _ = fs::remove_dir_all(&staging);
+    if res.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    res?;
+
+    let size = fs::metadata(&path)?.len();
+    Ok(BackupInfo { file: name, size })
+}
+
+/// Delete all but the newest `keep` backups, returns the removed file names.
+pub fn prune(backup_dir: &Path, keep: usize) -> anyhow::Result<Vec<String>> {
+    let mut backups: Vec<String> = fs::read_dir(backup_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("backup-") && name.ends_with(".tar.gz"))
+        .collect();
+    if keep == 0 || backups.len() <= keep {
+        return Ok(Vec::new());
+    }
+    // timestamps sort by name
+    backups.sort();
+    let stale = backups[..backups.len() - keep].to_vec();
+    for name in &stale {
+        fs::remove_file(backup_dir.join(name))?;
+    }
+    Ok(stale)
+}
+
+/// Back up this instance and apply the retention count.
+pub async fn backup() -> anyhow::Result<BackupInfo> {
+    let config = server_config();
+    let data_dir = DATA_DIR.get().unwrap();
+    let info = create(store::repo(), &config, data_dir).await?;
+    indoc_info!("Backup {} written, {} bytes.", info.file, info.size);
+    for name in prune(&data_dir.join(&config.backup.dir), config.backup.keep)? {
+        indoc_info!("Backup {name} removed by retention.");
+    }
+    Ok(info)
+}
+
+pub async fn block_periodic_backup() {
+    let hours = server_config().backup.interval_hours;
+    if hours == 0 {
+        return;
+    }
+    let period = Duration::from_secs(hours * 3600);
+    // first backup one period after startup
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = SHUTDOWN.requested() => break,
+        }
+        if let Err(e) = backup().await {
+            indoc_warn!(
+                "
+                Scheduled backup failed, error:
+                {e:#}
+                "
+            );
+        }
+    }
+}
+
+/// Extract archive into staging, checking every entry against the manifest.
+fn unpack(archive: &Path, staging: &Path) -> anyhow::Result<Manifest> {
+    let file = File::open(archive).with_context(|| format!("open {}", archive.display()))?;
+    let mut entries = tar::Archive::new(GzDecoder::new(file));
+    let mut manifest = None;
+    let mut unpacked = Vec::new();
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if !entry.header().entry_type().is_file() || !is_plain_relative(&name) {
+            bail!("unexpected entry {name}");
+        }
+        if name == MANIFEST {
+            manifest = Some(serde_json::from_reader::<_, Manifest>(&mut entry)?);
+            continue;
+        }
+        if unpacked.contains(&name) {
+            bail!("duplicate entry {name}");
+        }
+        let dest = staging.join(&name);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&dest)?;
+        unpacked.push(name);
+    }
+
+    let manifest = manifest.with_context(|| "no manifest, not a backup archive")?;
+    if manifest.format > BACKUP_FORMAT {
+        bail!("unsupported backup format {}", manifest.format);
+    }
+    for name in &unpacked {
+        let expected = manifest
+            .files
+            .get(name)
+            .with_context(|| format!("{name} not in manifest"))?;
+        if sha256_file(&staging.join(name))? != *expected {
+            bail!("checksum mismatch of {name}");
+        }
+    }
+    if let Some(missing) = manifest.files.keys().find(|f| !unpacked.contains(f)) {
+        bail!("{missing} missing from archive");
+    }
+    Ok(manifest)
+}
+
+/// Content checks beyond checksums, the archive may come from a broken server.
+/// Secret files of the config are looked up in data_dir, they are not archived.
+async fn validate(staging: &Path, manifest: &Manifest, data_dir: &Path) -> anyhow::Result<()> {
+    if !manifest.files.contains_key(DATABASE) {
+        bail!("archive has no {DATABASE}");
+    }
+    SqliteRepository::verify(&staging.join(DATABASE))
+        .await
+        .with_context(|| format!("archived {DATABASE}"))?;
+    if manifest.files.contains_key(CONFIG) {
+        let config = fs::read_to_string(staging.join(CONFIG))?;
+        config::parse_config_file(&config, data_dir)
+            .with_context(|| format!("archived {CONFIG}"))?;
+    }
+    Ok(())
+}
+
+fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to).with_context(|| format!("move {} to {}", from.display(), to.display()))
+}
+
+/// Validate archive, then swap its files into data_dir.
+/// Replaced files are moved aside, returns the directory keeping them.
+pub async fn restore(archive: &Path, data_dir: &Path) -> anyhow::Result<PathBuf> {
+    let created = stamp()?;
+    let staging = data_dir.join(format!(".restore-{created}"));
+    create_private_dir(&staging).with_context(|| "create staging directory")?;
+    let manifest = match unpack(archive, &staging) {
+        Ok(manifest) => validate(&staging, &manifest, data_dir)
+            .await
+            .map(|_| manifest),
+        Err(e) => Err(e),
+    };
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e.context(format!("invalid backup {}", archive.display())));
+        }
+    };
+
+    let saved = data_dir.join(format!("pre-restore-{created}"));
+    // leftover journal files would be replayed onto the restored database
+    let journals = ["store.db-wal", "store.db-shm"].map(String::from);
+    let replaced: Vec<_> = manifest
+        .files
+        .keys()
+        .chain(&journals)
+        .filter(|name| data_dir.join(name).exists())
+        .collect();
+    if !replaced.is_empty() {
+        create_private_dir(&saved).with_context(|| "create directory of replaced files")?;
+    }
+    for name in replaced {
+        move_file(&data_dir.join(name), &saved.join(name))?;
+    }
+    for name in manifest.files.keys() {
+        move_file(&staging.join(name), &data_dir.join(name))?;
+    }
+    fs::remove_dir_all(&staging)?;
+    Ok(saved)
+}
+
+/// Run the `backup` subcommand.
+pub async fn backup_command() -> anyhow::Result<()> {
+    let info = backup().await?;
+    println!("{}", info.file);
+    Ok(())
+}
+
+/// Run the `restore` subcommand, the server must be stopped.
+pub async fn restore_command(archive: &Path) -> anyhow::Result<()> {
+    let data_dir = DATA_DIR.get().unwrap();
+    let saved = restore(archive, data_dir).await?;
+    println!(
+        "Restored {}, replaced files kept in {}",
+        archive.display(),
+        saved.display()
+    );
+    Ok(())
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+    use crate::agent::ChatMessage;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-web-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let source = temp_dir();
+        let config = ServerConfig {
+            api_key: "sk-test".into(),
+            ..Default::default()
+        };
+        fs::write(source.join(CONFIG), toml::to_string(&config).unwrap()).unwrap();
+        fs::write(source.join("jwt_key"), [7; 32]).unwrap();
+        let repo = SqliteRepository::open(&source.join(DATABASE), 2)
+            .await
+            .unwrap();
+        repo.migrate().await.unwrap();
+        repo.persist_message(&ChatMessage::create_user("u", "kept"))
+            .await
+            .unwrap();
+
+        let info = create(&repo, &config, &source).await.unwrap();
+        let archive = source.join("backups").join(&info.file);
+        assert!(info.file.starts_with("backup-"));
+        assert_eq!(fs::metadata(&archive).unwrap().len(), info.size);
+        // nothing staged is left behind
+        assert_eq!(fs::read_dir(source.join("backups")).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&archive).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // restore over a data directory with other content
+        let target = temp_dir();
+        fs::write(target.join(DATABASE), b"stale").unwrap();
+        fs::write(target.join("store.db-wal"), b"stale").unwrap();
+        let saved = restore(&archive, &target).await.unwrap();
+        assert_eq!(fs::read(saved.join(DATABASE)).unwrap(), b"stale");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&saved).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        assert!(!target.join("store.db-wal").exists());
+        assert_eq!(fs::read(target.join("jwt_key")).unwrap(), [7; 32]);
+        let restored = SqliteRepository::open(&target.join(DATABASE), 1)
+            .await
+            .unwrap();
+        let history = restored.load_history("u").await.unwrap();
+        assert_eq!(history[0].content, "kept");
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_archive() {
+        let dir = temp_dir();
+        let target = temp_dir();
+        fs::write(target.join(DATABASE), b"current").unwrap();
+
+        // checksums match but the database is garbage
+        fs::write(dir.join("fake.db"), b"not a database").unwrap();
+        let archive = dir.join("fake.tar.gz");
+        let files = [(DATABASE.to_string(), dir.join("fake.db"))];
+        write_archive(&archive, "20250101-000000", &files).unwrap();
+        assert!(restore(&archive, &target).await.is_err());
+
+        // not an archive at all
+        fs::write(dir.join("junk.tar.gz"), b"junk").unwrap();
+        assert!(restore(&dir.join("junk.tar.gz"), &target).await.is_err());
+
+        // the data directory is untouched
+        assert_eq!(fs::read(target.join(DATABASE)).unwrap(), b"current");
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 1);
+
+        assert!(!is_plain_relative("../jwt_key"));
+        assert!(!is_plain_relative("/etc/passwd"));
+        assert!(is_plain_relative("keys/master_key"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    /// `api_key` may come from `AGENT_WEB_API_KEY`, the archived file is checked by keys and types.
+    #[tokio::test]
+    async fn restore_config_without_api_key() {
+        let dir = temp_dir();
+        let target = temp_dir();
+        let repo = SqliteRepository::open(&dir.join("live.db"), 1)
+            .await
+            .unwrap();
+        repo.migrate().await.unwrap();
+        repo.snapshot(&dir.join(DATABASE)).await.unwrap();
+
+        fs::write(dir.join(CONFIG), "model = \"gpt-4o\"\n").unwrap();
+        let files = [
+            (DATABASE.to_string(), dir.join(DATABASE)),
+            (CONFIG.to_string(), dir.join(CONFIG)),
+        ];
+        let archive = dir.join("env.tar.gz");
+        write_archive(&archive, "20250101-000000", &files).unwrap();
+        restore(&archive, &target).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(target.join(CONFIG)).unwrap(),
+            "model = \"gpt-4o\"\n"
+        );
+
+        fs::write(dir.join(CONFIG), "modle = \"gpt-4o\"\n").unwrap();
+        let archive = dir.join("typo.tar.gz");
+        write_archive(&archive, "20250101-000000", &files).unwrap();
+        let err = restore(&archive, &target).await.unwrap_err();
+        assert!(format!("{err:#}").contains("modle"), "{err:#}");
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        let dir = temp_dir();
+        for stamp in ["20250103", "20250101", "20250102"] {
+            fs::write(dir.join(format!("backup-{stamp}-000000.tar.gz")), b"").unwrap();
+        }
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+        assert!(prune(&dir, 0).unwrap().is_empty());
+        assert_eq!(prune(&dir, 2).unwrap(), ["backup-20250101-000000.tar.gz"]);
+        assert!(prune(&dir, 2).unwrap().is_empty());
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
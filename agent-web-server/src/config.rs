@@ -1,13 +1,16 @@
-use crate::states::DATA_DIR;
-use anyhow::{Context, Result};
+use crate::{states::DATA_DIR, validate};
+use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
-use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
-};
+use serde_aux::serde_introspection::serde_introspect;
+use std::{collections::BTreeMap, env, fs, io::ErrorKind, path::Path};
+use toml::{Table, Value};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Absent fields take their default, see [layered_config].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct ServerConfig {
+    /// `postgres://...` to use PostgreSQL, SQLite `store.db` in data directory if absent.
+    pub database_url: Option<String>,
     pub db_pool_size: u32,
     pub jwt_expire_days: u64,
     pub chat_expire_days: u64,
@@ -15,54 +18,657 @@ pub struct ServerConfig {
     pub api_key: String,
     pub model: String,
     pub sys_prompt: String,
+    pub rate_limit: RateLimitConfig,
+    pub quota: QuotaConfig,
+    /// Model name -> price, completions of unlisted models cost nothing.
+    pub prices: BTreeMap<String, ModelPrice>,
+    pub budget: BudgetConfig,
+    pub encryption: EncryptionConfig,
+    pub backup: BackupConfig,
+    pub retention: RetentionConfig,
+    pub listen: ListenConfig,
+    /// On shutdown, generations still running after this are interrupted.
+    pub shutdown_deadline_secs: u64,
+    pub health: HealthConfig,
+    pub telemetry: TelemetryConfig,
+    pub log: LogConfig,
+}
+
+/// Where to serve, see [crate::listen].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ListenConfig {
+    /// IPv4 or IPv6 address to bind, port given by `--port`.
+    pub address: String,
+    /// Serve on this Unix socket instead of TCP, e.g. behind a local reverse proxy.
+    /// Relative to data directory.
+    pub unix_socket: Option<String>,
+    /// Terminate TLS, plain HTTP if absent.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".into(),
+            unix_socket: None,
+            tls: None,
+        }
+    }
+}
+
+/// PEM files relative to data directory, reloaded when they change.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first.
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// Price in dollars per 1K tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ModelPrice {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+fn default_prices() -> BTreeMap<String, ModelPrice> {
+    let mut prices = BTreeMap::new();
+    prices.insert(
+        "chatgpt-4o-latest".into(),
+        ModelPrice {
+            input_per_1k: 0.005,
+            output_per_1k: 0.015,
+        },
+    );
+    prices
+}
+
+/// Daily spend thresholds in dollars, alerts fire once per day and scope.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct BudgetConfig {
+    pub global_daily: Option<f64>,
+    pub user_daily: Option<f64>,
+    /// Receives alerts as JSON POST, alerts are only logged if absent.
+    pub webhook_url: Option<String>,
+}
+
+/// Days of inactivity before a conversation is purged, 0 keeps it forever.
+/// Conversations of users without override expire after `chat_expire_days`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Seconds between purges.
+    pub interval_secs: u64,
+    /// Role name -> days.
+    pub roles: BTreeMap<String, u64>,
+    /// User uuid -> days, takes precedence over the role.
+    pub users: BTreeMap<String, u64>,
+    /// Days cleared history stays restorable before it is purged.
+    pub trash_days: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 600,
+            roles: BTreeMap::new(),
+            users: BTreeMap::new(),
+            trash_days: 7,
+        }
+    }
+}
+
+/// Checks of `/readyz`, see [crate::health].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Also list models of the provider, a failure reports degraded.
+    pub upstream_ping: bool,
+    /// Seconds a ping result is reused, so that probes cost the provider little.
+    pub upstream_cache_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            upstream_ping: false,
+            upstream_cache_secs: 60,
+        }
+    }
+}
+
+/// Traces exported over OTLP/HTTP, see [crate::telemetry].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Collector base URL, e.g. `http://localhost:4318`, spans are posted to `/v1/traces`.
+    /// Nothing is exported if absent.
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of exported spans.
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").into(),
+        }
+    }
+}
+
+/// Log output to stdout and files, see [crate::tracing].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Format on stdout.
+    pub format: LogFormat,
+    pub file_format: LogFormat,
+    /// Offset of timestamps like `+08:00`, `UTC`, or `local` for the system's at startup.
+    pub timezone: String,
+    /// Relative to data directory, `server_log` next to the executable if absent.
+    pub dir: Option<String>,
+    /// Files are named `<file_prefix>.<date>`, or just by date if empty.
+    pub file_prefix: String,
+    /// Files start over on the UTC clock, whatever the timezone.
+    pub rotation: LogRotation,
+    /// Oldest files beyond this many are deleted on rotation, 0 keeps all.
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            file_format: LogFormat::Pretty,
+            timezone: "+08:00".into(),
+            dir: None,
+            file_prefix: String::new(),
+            rotation: LogRotation::Daily,
+            max_files: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line, with location and span fields.
+    Pretty,
+    /// One line per event.
+    Compact,
+    /// One object per line, for log pipelines.
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// Archives of database, config and keys, see [crate::backup].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Relative to data directory.
+    pub dir: String,
+    /// Take a backup every this many hours while serving, 0 to disable.
+    pub interval_hours: u64,
+    /// Newest backups kept, older ones are deleted, 0 to keep all.
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: "backups".into(),
+            interval_hours: 0,
+            keep: 7,
+        }
+    }
+}
+
+/// Envelope encryption of message content, see [crate::crypto].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    /// 32 raw bytes, relative to data directory, generated if missing.
+    /// `AGENT_WEB_MASTER_KEY` (base64) takes precedence.
+    pub key_file: String,
+    /// Retired keys still needed to decrypt rows not yet rotated.
+    pub previous_key_files: Vec<String>,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key_file: "master_key".into(),
+            previous_key_files: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Keep buckets in the database so limits survive restarts.
+    pub persist: bool,
+    /// Route path -> limits, routes not listed are unlimited.
+    pub routes: BTreeMap<String, RouteLimit>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RouteLimit {
+    pub per_user: Option<BucketRule>,
+    pub per_ip: Option<BucketRule>,
+}
+
+/// Token bucket holding up to `capacity` calls, refilled by `refill_per_min`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BucketRule {
+    pub capacity: f64,
+    pub refill_per_min: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Role name -> quota, roles not listed are unlimited.
+    pub roles: BTreeMap<String, Quota>,
+    /// User uuid -> quota, takes precedence over the role quota.
+    pub users: BTreeMap<String, Quota>,
+}
+
+/// Total tokens (prompt + completion) allowed per period, `None` for unlimited.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub daily_tokens: Option<i64>,
+    pub monthly_tokens: Option<i64>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        let mut roles = BTreeMap::new();
+        roles.insert(
+            "user".into(),
+            Quota {
+                daily_tokens: Some(100_000),
+                monthly_tokens: Some(1_000_000),
+            },
+        );
+        Self {
+            roles,
+            users: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let mut routes = BTreeMap::new();
+        routes.insert(
+            "/init-session".into(),
+            RouteLimit {
+                per_user: None,
+                per_ip: Some(BucketRule {
+                    capacity: 10.0,
+                    refill_per_min: 1.0,
+                }),
+            },
+        );
+        routes.insert(
+            "/ask-agent".into(),
+            RouteLimit {
+                per_user: Some(BucketRule {
+                    capacity: 20.0,
+                    refill_per_min: 6.0,
+                }),
+                per_ip: Some(BucketRule {
+                    capacity: 60.0,
+                    refill_per_min: 20.0,
+                }),
+            },
+        );
+        Self {
+            persist: false,
+            routes,
+        }
+    }
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
+            database_url: None,
             db_pool_size: 10,
             chat_expire_days: 30,
             jwt_expire_days: 30,
             api_base: "https://api.openai.com/v1".into(),
-            api_key: "<API Key>".into(),
+            api_key: validate::API_KEY_PLACEHOLDER.into(),
             model: "chatgpt-4o-latest".into(),
             sys_prompt: "You are a helpful assistant.".into(),
+            rate_limit: RateLimitConfig::default(),
+            quota: QuotaConfig::default(),
+            prices: default_prices(),
+            budget: BudgetConfig::default(),
+            encryption: EncryptionConfig::default(),
+            backup: BackupConfig::default(),
+            retention: RetentionConfig::default(),
+            listen: ListenConfig::default(),
+            shutdown_deadline_secs: 30,
+            health: HealthConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+/// In data directory.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Prefix of environment variables overriding config fields.
+/// Nested keys are separated by `__`, e.g. `AGENT_WEB_RETENTION__TRASH_DAYS=30`.
+pub const ENV_PREFIX: &str = "AGENT_WEB_";
+
+/// Secret fields, each can also be read from `<field>_file`,
+/// e.g. `api_key_file` in config or `AGENT_WEB_API_KEY_FILE`.
+pub const SECRET_KEYS: &[&str] = &["api_key", "database_url", "budget.webhook_url"];
+
+const FILE_SUFFIX: &str = "_file";
+
+fn get_path<'a>(table: &'a Table, path: &[&str]) -> Option<&'a Value> {
+    let (last, parents) = path.split_last()?;
+    let mut table = table;
+    for key in parents {
+        table = table.get(*key)?.as_table()?;
+    }
+    table.get(*last)
+}
+
+/// Insert value at dotted path, creating missing tables.
+fn insert_path(table: &mut Table, path: &[&str], value: Value) -> Result<()> {
+    let Some((last, parents)) = path.split_last() else {
+        bail!("empty key");
+    };
+    let mut table = table;
+    for key in parents {
+        table = table
+            .entry(*key)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .with_context(|| format!("{key} is not a table"))?;
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// Raw override value, kept a string if current one is, otherwise read as TOML
+/// (`30`, `true`, `["a", "b"]`, `{ user = 7 }`) with string as fallback.
+fn parse_value(raw: &str, current: Option<&Value>) -> Value {
+    if let Some(Value::String(_)) = current {
+        return Value::String(raw.into());
+    }
+    toml::from_str::<Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.into()))
+}
+
+fn read_secret(file: &str, data_dir: &Path) -> Result<Value> {
+    let content = fs::read_to_string(data_dir.join(file))
+        .with_context(|| format!("read secret file {file}"))?;
+    Ok(Value::String(content.trim_end().into()))
+}
+
+/// Secret whose file key is `key`, e.g. `api_key` of `api_key_file`.
+fn secret_of_file_key(key: &str) -> Option<&'static str> {
+    let secret = key.strip_suffix(FILE_SUFFIX)?;
+    SECRET_KEYS.iter().copied().find(|s| *s == secret)
+}
+
+/// Replace `<secret>_file` keys of the config file by the file content.
+fn read_secret_files(config: &mut Table, data_dir: &Path) -> Result<()> {
+    for secret in SECRET_KEYS {
+        let path: Vec<_> = secret.split('.').collect();
+        let (last, parents) = path.split_last().unwrap();
+        let mut table = Some(&mut *config);
+        for key in parents {
+            table = table
+                .and_then(|t| t.get_mut(*key))
+                .and_then(Value::as_table_mut);
         }
+        let Some(table) = table else {
+            continue;
+        };
+        let Some(file) = table.remove(&format!("{last}{FILE_SUFFIX}")) else {
+            continue;
+        };
+        let file = file
+            .as_str()
+            .with_context(|| format!("{secret}{FILE_SUFFIX} is not a string"))?;
+        table.insert(last.to_string(), read_secret(file, data_dir)?);
     }
+    Ok(())
 }
 
-/// Initialize config, either read or create.  
+/// Overrides in `(dotted key, raw value)` pairs, from environment variables named
+/// after fields, other variables with the prefix are left alone.
+fn env_overrides(vars: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let fields = serde_introspect::<ServerConfig>();
+    let is_field = |key: &str| fields.contains(&key) || secret_of_file_key(key).is_some();
+    vars.into_iter()
+        .filter_map(|(name, value)| {
+            let key = name
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .replace("__", ".");
+            let top = key.split('.').next().unwrap_or_default();
+            is_field(top).then_some((key, value))
+        })
+        .collect()
+}
+
+/// Parse `KEY=VALUE` of `--set`.
+fn cli_overrides(sets: &[String]) -> Result<Vec<(String, String)>> {
+    sets.iter()
+        .map(|set| {
+            let (key, value) = set
+                .split_once('=')
+                .with_context(|| format!("--set {set}: expect KEY=VALUE"))?;
+            Ok((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Config from layers, each one overriding the previous:
+/// defaults, config file, environment variables, then `--set` flags.  
 ///
-/// ([ServerConfig], true) if read from existing config,  
-/// ([ServerConfig], false) if created a new config.
-pub fn init_config() -> Result<(ServerConfig, bool)> {
+/// Tables of the file fill up defaults field by field, overrides replace the value at their key.
+pub fn layered_config(
+    file: Option<&str>,
+    env: Vec<(String, String)>,
+    sets: &[String],
+    data_dir: &Path,
+) -> Result<ServerConfig> {
+    let merged = merge_layers(file, env, sets, data_dir)?;
+    Ok(validate::deserialize(merged)?)
+}
+
+fn merge_layers(
+    file: Option<&str>,
+    env: Vec<(String, String)>,
+    sets: &[String],
+    data_dir: &Path,
+) -> Result<Value> {
+    let Value::Table(defaults) =
+        Value::try_from(ServerConfig::default()).with_context(|| "serialize default config")?
+    else {
+        unreachable!("config is a table");
+    };
+    let mut config = match file {
+        Some(file) => toml::from_str(file).with_context(|| "parse config file")?,
+        None => Table::new(),
+    };
+    read_secret_files(&mut config, data_dir)?;
+    for (key, raw) in env_overrides(env).into_iter().chain(cli_overrides(sets)?) {
+        let (key, value) = match secret_of_file_key(&key) {
+            Some(secret) => (secret.to_string(), read_secret(&raw, data_dir)?),
+            None => {
+                let path: Vec<_> = key.split('.').collect();
+                let current = get_path(&config, &path).or_else(|| get_path(&defaults, &path));
+                let value = parse_value(&raw, current);
+                (key, value)
+            }
+        };
+        let path: Vec<_> = key.split('.').collect();
+        insert_path(&mut config, &path, value).with_context(|| format!("override {key}"))?;
+    }
+    Ok(Value::Table(config))
+}
+
+/// Run the `check-config` subcommand, validating all layers without touching the database.
+pub fn check_config_command(sets: &[String]) -> Result<()> {
+    load_config(sets)?;
+    let config_path = DATA_DIR.get().unwrap().join(CONFIG_FILE);
+    println!("{} with overrides is valid", config_path.display());
+    Ok(())
+}
+
+/// Config file content over defaults, without overrides.
+/// Only keys and types are checked, values may rely on overrides of the deployment.
+pub fn parse_config_file(file: &str, data_dir: &Path) -> Result<ServerConfig> {
+    let merged = merge_layers(Some(file), Vec::new(), &[], data_dir)?;
+    Ok(validate::deserialize_structure(merged)?)
+}
+
+/// Content of `config.toml` in data directory, `None` if missing.
+fn read_config_file() -> Result<Option<String>> {
+    let config_path = DATA_DIR.get().unwrap().join(CONFIG_FILE);
+    match fs::read_to_string(&config_path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| "read server config"),
+    }
+}
+
+/// Config from all layers as they are now, e.g. to reload.
+pub fn load_config(sets: &[String]) -> Result<ServerConfig> {
+    let file = read_config_file()?;
     let data_dir = DATA_DIR.get().unwrap();
-    let config_path = data_dir.join("config.toml");
-
-    match OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create_new(true)
-        .open(&config_path)
-    {
-        Ok(mut config_file) => {
-            let template = ServerConfig::default();
-            let toml_str =
-                toml::to_string_pretty(&template).with_context(|| "serialize server config")?;
-            config_file
-                .write_all(toml_str.as_bytes())
-                .with_context(|| "write server config")?;
-            Ok((ServerConfig::default(), false))
-        }
-        Err(_) => {
-            let mut config_file =
-                File::open(&config_path).with_context(|| "open existing server config")?;
-            let mut toml_str = String::new();
-            config_file
-                .read_to_string(&mut toml_str)
-                .with_context(|| "read server config")?;
-            let config = toml::from_str(&toml_str).with_context(|| "deserialize config")?;
-            Ok((config, true))
-        }
+    layered_config(file.as_deref(), env::vars().collect(), sets, data_dir)
+}
+
+/// Initialize config from `config.toml` in data directory, environment and `--set` flags.  
+///
+/// ([ServerConfig], true) if the config file exists,  
+/// ([ServerConfig], false) if running on defaults and overrides only.
+pub fn init_config(sets: &[String]) -> Result<(ServerConfig, bool)> {
+    let has_config = read_config_file()?.is_some();
+    Ok((load_config(sets)?, has_config))
+}
+
+/// Run the `init-config` subcommand, writing the defaults as a template.
+pub fn init_config_command(force: bool) -> Result<()> {
+    let config_path = DATA_DIR.get().unwrap().join(CONFIG_FILE);
+    if config_path.exists() && !force {
+        bail!(
+            "{} exists, pass --force to overwrite",
+            config_path.display()
+        );
+    }
+    let template = toml::to_string_pretty(&ServerConfig::default())
+        .with_context(|| "serialize server config")?;
+    fs::write(&config_path, template).with_context(|| "write server config")?;
+    println!(
+        "{} written, set api_key before serving",
+        config_path.display()
+    );
+    Ok(())
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn layer_precedence() {
+        let dir = std::env::temp_dir();
+        let file = r#"
+            model = "from-file"
+            api_key = "file-key"
+            db_pool_size = 3
+            [retention]
+            trash_days = 1
+        "#;
+        let env = vars(&[
+            ("AGENT_WEB_MODEL", "from-env"),
+            ("AGENT_WEB_DB_POOL_SIZE", "4"),
+            ("AGENT_WEB_RETENTION__ROLES", "{ user = 9 }"),
+            ("AGENT_WEB_BUDGET__GLOBAL_DAILY", "2.5"),
+            ("AGENT_WEB_SYS_PROMPT", "42"),
+            // not fields, e.g. master key of encryption
+            ("AGENT_WEB_MASTER_KEY", "x"),
+            ("HOME", "/root"),
+        ]);
+        let sets = ["model=from-cli".to_string()];
+        let config = layered_config(Some(file), env, &sets, &dir).unwrap();
+        assert_eq!(config.model, "from-cli");
+        assert_eq!(config.api_key, "file-key");
+        assert_eq!(config.db_pool_size, 4);
+        assert_eq!(config.sys_prompt, "42");
+        assert_eq!(config.retention.trash_days, 1);
+        assert_eq!(config.retention.roles.get("user"), Some(&9));
+        assert_eq!(config.budget.global_daily, Some(2.5));
+        // untouched fields keep their default
+        assert_eq!(config.jwt_expire_days, 30);
+        assert!(config.quota.roles.contains_key("user"));
+
+        assert!(layered_config(None, Vec::new(), &["model".into()], &dir).is_err());
+        let sets = ["db_pool_size=many".to_string()];
+        assert!(layered_config(None, Vec::new(), &sets, &dir).is_err());
+    }
+
+    #[test]
+    fn secret_files() {
+        let dir = std::env::temp_dir().join(format!("agent-web-config-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("api_key"), "secret-key\n").unwrap();
+        fs::write(dir.join("hook"), "https://hooks.example/alert").unwrap();
+        fs::write(dir.join("db"), "postgres://agent:pw@db/agent\n").unwrap();
+
+        let file = "api_key_file = \"api_key\"\n[budget]\nwebhook_url_file = \"hook\"";
+        let config = parse_config_file(file, &dir).unwrap();
+        assert_eq!(config.api_key, "secret-key");
+        assert_eq!(
+            config.budget.webhook_url.as_deref(),
+            Some("https://hooks.example/alert")
+        );
+
+        // a file in a later layer wins over a value in an earlier one
+        let env = vars(&[("AGENT_WEB_DATABASE_URL_FILE", "db")]);
+        let config = layered_config(Some("api_key = \"plain\""), env, &[], &dir).unwrap();
+        assert_eq!(
+            config.database_url.as_deref(),
+            Some("postgres://agent:pw@db/agent")
+        );
+        assert_eq!(config.api_key, "plain");
+        let sets = ["api_key_file=missing".to_string()];
+        assert!(layered_config(None, Vec::new(), &sets, &dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
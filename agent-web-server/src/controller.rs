@@ -1,12 +1,22 @@
 use crate::{
-    agent::{self, ChatMessage},
-    auth::{AuthReq, JwtClaim, gen_jwt},
+    agent::{self, ChatMessage, HistoryCursor, MessageStatus},
+    auth::{ApiKey, AuthReq, JwtClaim, Role, User, gen_api_key, gen_jwt, hash_api_key},
+    backup::{self, BackupInfo},
+    cost::{self, CostReport},
+    export::{self, ConversationExport, ExportFormat},
     indoc_debug, indoc_info, indoc_warn,
     protocol::AppResp,
-    store,
+    retention::{self, PurgeReport, PurgeRun},
+    search::{self, SearchPage},
+    shutdown::{Generation, SHUTDOWN},
+    states::server_config,
+    store, telemetry,
+    usage::{self, UsageReport},
 };
-use axum::Json;
+use axum::{Json, http::StatusCode};
 use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tracing::Instrument;
 
 // Util
 
@@ -20,31 +30,119 @@ fn err<T: Serialize>(err: impl Into<String>) -> JsonResp<T> {
     Json(AppResp::Exception(err.into()))
 }
 
+fn history_resp(history: Option<Vec<ChatMessage>>) -> JsonResp<Vec<ChatMessage>> {
+    match history {
+        Some(history) => ok(history),
+        None => err("Failed to load history."),
+    }
+}
+
 // API
 
-pub async fn init_session() -> JsonResp<String> {
+pub async fn init_session() -> Result<JsonResp<String>, (StatusCode, String)> {
     let uuid = uuid::Uuid::new_v4().to_string();
-    let jwt = gen_jwt(JwtClaim { uuid });
-    ok(jwt)
+    if !User::create(&uuid, Role::User).await {
+        return Err((
+            // a token without its user would be rejected on every call
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Failed to create session, try again later.".to_string(),
+        ));
+    }
+    let jwt = gen_jwt(JwtClaim {
+        uuid,
+        role: Role::User,
+    });
+    Ok(ok(jwt))
 }
 
-pub async fn fetch_history(req: AuthReq<()>) -> JsonResp<Vec<ChatMessage>> {
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+#[derive(Deserialize)]
+pub struct FetchHistoryReq {
+    /// Message id, fetch older messages.
+    before: Option<i64>,
+    /// Message id, fetch newer messages.
+    after: Option<i64>,
+    limit: Option<u32>,
+}
+/// Body may be null for the whole history,
+/// otherwise a page of the latest messages or next to a cursor, oldest first.
+pub async fn fetch_history(req: AuthReq<Option<FetchHistoryReq>>) -> JsonResp<Vec<ChatMessage>> {
     let uuid = req.claim.uuid;
-    let history = ChatMessage::load_all(&uuid).await;
-    ok(history)
+    telemetry::record_conversation(&uuid);
+    let Some(page) = req.body else {
+        return history_resp(ChatMessage::load_all(&uuid).await);
+    };
+    let cursor = match (page.before, page.after) {
+        (None, None) => HistoryCursor::Latest,
+        (Some(id), None) => HistoryCursor::Before(id),
+        (None, Some(id)) => HistoryCursor::After(id),
+        (Some(_), Some(_)) => return err("Only one of before and after is allowed."),
+    };
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    history_resp(ChatMessage::load_page(&uuid, cursor, limit).await)
 }
 
 pub async fn clear_history(req: AuthReq<()>) -> JsonResp<()> {
     let uuid = req.claim.uuid;
+    telemetry::record_conversation(&uuid);
     store::clear_history_by_uuid(&uuid).await;
     ok(())
 }
 
+/// Bring back history cleared within the trash window, returns messages restored.
+pub async fn restore_history(req: AuthReq<()>) -> JsonResp<u64> {
+    let uuid = req.claim.uuid;
+    telemetry::record_conversation(&uuid);
+    ok(store::restore_history_by_uuid(&uuid).await)
+}
+
+/// Records a cancelled answer if the handler is dropped while waiting for the agent,
+/// which axum does when the client disconnects.
+struct PendingReply {
+    uuid: String,
+    started: Instant,
+    done: bool,
+    _generation: Generation<'static>,
+}
+
+impl PendingReply {
+    fn new(uuid: &str) -> Self {
+        Self {
+            uuid: uuid.to_string(),
+            started: Instant::now(),
+            done: false,
+            _generation: SHUTDOWN.generation(),
+        }
+    }
+
+    fn failed(&self, status: MessageStatus) -> ChatMessage {
+        let config = server_config();
+        let latency_ms = self.started.elapsed().as_millis() as i64;
+        ChatMessage::create_failed(&self.uuid, &config.model, latency_ms, status)
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut message = self.failed(MessageStatus::Cancelled);
+        tokio::spawn(async move { message.persist().await });
+    }
+}
+
 #[derive(Deserialize)]
 pub struct AskAgentReq {
     message: String,
 }
 pub async fn ask_agent(req: AuthReq<AskAgentReq>) -> JsonResp<()> {
+    telemetry::record_conversation(&req.claim.uuid);
     indoc_debug!(
         "
         ip: {:?}
@@ -55,25 +153,280 @@ pub async fn ask_agent(req: AuthReq<AskAgentReq>) -> JsonResp<()> {
         req.claim.uuid,
         req.body.message
     );
+    if let Err(reason) = usage::check_quota(&req.claim).await {
+        return err(reason);
+    }
     let uuid = req.claim.uuid;
     let msg = req.body.message;
-    let query_message = ChatMessage::create_user(&uuid, &msg);
+    let mut query_message = ChatMessage::create_user(&uuid, &msg);
     query_message.persist().await;
-    let mut history = ChatMessage::load_all(&uuid).await;
+    let Some(mut history) = ChatMessage::load_all(&uuid).await else {
+        return err("Failed to load history.");
+    };
     history.push(query_message);
-    match agent::send_request(history).await {
-        Ok(content) => {
-            let reply_message = ChatMessage::create_assistant(&uuid, &content);
+    let mut pending = PendingReply::new(&uuid);
+    let res = tokio::select! {
+        res = agent::send_request(history) => res,
+        _ = SHUTDOWN.interrupted() => {
+            pending.done = true;
+            pending.failed(MessageStatus::Interrupted).persist().await;
+            return err("Server is shutting down, ask again later.");
+        }
+    };
+    pending.done = true;
+    match res {
+        Ok(reply) => {
+            let config = server_config();
+            let model = &config.model;
+            let cost = cost::estimate(config.prices.get(model), &reply.usage);
+            reply.usage.record(&uuid, model, cost).await;
+            tokio::spawn(cost::check_budget(uuid.clone()).in_current_span());
+            let mut reply_message = ChatMessage::create_assistant(&uuid, &reply);
             reply_message.persist().await;
             ok(())
         }
         Err(e) => {
             indoc_warn!("Agent Error: {e}");
+            pending.failed(MessageStatus::Error).persist().await;
             err(e.to_string())
         }
     }
 }
 
+#[derive(Deserialize)]
+pub struct UsageReq {
+    days: u32,
+}
+/// Body may be null, defaults to the last 30 days.
+pub async fn fetch_usage(req: AuthReq<Option<UsageReq>>) -> JsonResp<UsageReport> {
+    let days = req.body.map_or(30, |b| b.days);
+    let report = usage::report(&req.claim, days).await;
+    ok(report)
+}
+
+#[derive(Deserialize)]
+pub struct SearchHistoryReq {
+    query: String,
+    #[serde(default)]
+    offset: u32,
+    limit: Option<u32>,
+}
+pub async fn search_history(req: AuthReq<SearchHistoryReq>) -> JsonResp<SearchPage> {
+    let SearchHistoryReq {
+        query,
+        offset,
+        limit,
+    } = req.body;
+    if query.trim().is_empty() {
+        return err("Search query is empty.");
+    }
+    telemetry::record_conversation(&req.claim.uuid);
+    let page = search::search(&req.claim.uuid, &query, offset, limit).await;
+    ok(page)
+}
+
+#[derive(Deserialize)]
+pub struct ExportReq {
+    format: ExportFormat,
+}
+#[derive(Serialize)]
+pub struct ExportResp {
+    /// Suggested file name to save content as.
+    filename: String,
+    content: String,
+}
+pub async fn export_history(req: AuthReq<ExportReq>) -> JsonResp<ExportResp> {
+    export_conversation(&req.claim.uuid, req.body.format).await
+}
+
+async fn export_conversation(uuid: &str, format: ExportFormat) -> JsonResp<ExportResp> {
+    telemetry::record_conversation(uuid);
+    let config = server_config();
+    let res = export::load(store::repo(), uuid, &config.sys_prompt)
+        .await
+        .and_then(|c| export::render(&[c], format));
+    match res {
+        Ok(content) => ok(ExportResp {
+            filename: format!("conversation-{uuid}.{}", format.extension()),
+            content,
+        }),
+        Err(e) => {
+            indoc_warn!("Export conversation failed, error: {e:#}");
+            err("Failed to export conversation.")
+        }
+    }
+}
+
+/// Body is a conversation in the JSON export format,
+/// imported into the caller's history, which must be empty.
+pub async fn import_history(req: AuthReq<ConversationExport>) -> JsonResp<usize> {
+    telemetry::record_conversation(&req.claim.uuid);
+    match export::import(store::repo(), &req.body, &req.claim.uuid).await {
+        Ok(count) => ok(count),
+        Err(e) => err(format!("{e:#}")),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyReq {
+    name: String,
+}
+#[derive(Serialize)]
+pub struct CreateApiKeyResp {
+    id: i64,
+    name: String,
+    /// The only time the plain key is revealed.
+    key: String,
+}
+pub async fn create_api_key(req: AuthReq<CreateApiKeyReq>) -> JsonResp<CreateApiKeyResp> {
+    let uuid = req.claim.uuid;
+    let name = req.body.name;
+    let key = gen_api_key();
+    let prefix = &key[..12];
+    match ApiKey::create(&uuid, &name, &hash_api_key(&key), prefix).await {
+        Some(id) => ok(CreateApiKeyResp { id, name, key }),
+        None => err("Failed to create API key."),
+    }
+}
+
+pub async fn list_api_keys(req: AuthReq<()>) -> JsonResp<Vec<ApiKey>> {
+    let uuid = req.claim.uuid;
+    let keys = ApiKey::list_active(&uuid).await;
+    ok(keys)
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApiKeyReq {
+    id: i64,
+}
+pub async fn revoke_api_key(req: AuthReq<RevokeApiKeyReq>) -> JsonResp<()> {
+    let uuid = req.claim.uuid;
+    if ApiKey::revoke(&uuid, req.body.id).await {
+        ok(())
+    } else {
+        err("No such API key.")
+    }
+}
+
+// Admin API
+
+pub async fn admin_list_users(_req: AuthReq<()>) -> JsonResp<Vec<User>> {
+    let users = User::list_all().await;
+    ok(users)
+}
+
+#[derive(Deserialize)]
+pub struct TargetUserReq {
+    uuid: String,
+}
+
+pub async fn admin_fetch_history(req: AuthReq<TargetUserReq>) -> JsonResp<Vec<ChatMessage>> {
+    telemetry::record_conversation(&req.body.uuid);
+    history_resp(ChatMessage::load_all(&req.body.uuid).await)
+}
+
+#[derive(Deserialize)]
+pub struct AdminExportReq {
+    uuid: String,
+    format: ExportFormat,
+}
+pub async fn admin_export_history(req: AuthReq<AdminExportReq>) -> JsonResp<ExportResp> {
+    export_conversation(&req.body.uuid, req.body.format).await
+}
+
+pub async fn admin_clear_history(req: AuthReq<TargetUserReq>) -> JsonResp<()> {
+    telemetry::record_conversation(&req.body.uuid);
+    indoc_info!(
+        "
+        Admin {} clears history of {}
+        ",
+        req.claim.uuid,
+        req.body.uuid
+    );
+    store::clear_history_by_uuid(&req.body.uuid).await;
+    ok(())
+}
+
+pub async fn admin_restore_history(req: AuthReq<TargetUserReq>) -> JsonResp<u64> {
+    telemetry::record_conversation(&req.body.uuid);
+    indoc_info!(
+        "
+        Admin {} restores history of {}
+        ",
+        req.claim.uuid,
+        req.body.uuid
+    );
+    ok(store::restore_history_by_uuid(&req.body.uuid).await)
+}
+
+#[derive(Deserialize)]
+pub struct CostReq {
+    days: u32,
+}
+/// Body may be null, defaults to the last 30 days.
+pub async fn admin_cost(req: AuthReq<Option<CostReq>>) -> JsonResp<CostReport> {
+    let days = req.body.map_or(30, |b| b.days);
+    let report = cost::report(days).await;
+    ok(report)
+}
+
+/// Backup written to the backup directory of the server, older ones pruned.
+pub async fn admin_backup(req: AuthReq<()>) -> JsonResp<BackupInfo> {
+    indoc_info!("Admin {} takes a backup", req.claim.uuid);
+    match backup::backup().await {
+        Ok(info) => ok(info),
+        Err(e) => {
+            indoc_warn!("Backup failed, error: {e:#}");
+            err("Failed to take backup.")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PurgeReq {
+    dry_run: bool,
+}
+/// Purge conversations past retention now, or only list them on dry run.
+pub async fn admin_purge(req: AuthReq<PurgeReq>) -> JsonResp<PurgeReport> {
+    let config = server_config();
+    let source = format!("admin {}", req.claim.uuid);
+    match retention::purge(store::repo(), &config, req.body.dry_run, &source).await {
+        Ok(report) => ok(report),
+        Err(e) => {
+            indoc_warn!("Purge failed, error: {e:#}");
+            err("Failed to purge history.")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PurgeRunsReq {
+    limit: u32,
+}
+/// Body may be null, defaults to the last 50 runs.
+pub async fn admin_purge_runs(req: AuthReq<Option<PurgeRunsReq>>) -> JsonResp<Vec<PurgeRun>> {
+    let limit = req.body.map_or(50, |b| b.limit).min(1000);
+    ok(PurgeRun::list(limit).await)
+}
+
+#[derive(Deserialize)]
+pub struct PinHistoryReq {
+    uuid: String,
+    pinned: bool,
+}
+/// Pinned conversations are kept forever, whatever the retention.
+pub async fn admin_pin_history(req: AuthReq<PinHistoryReq>) -> JsonResp<()> {
+    telemetry::record_conversation(&req.body.uuid);
+    indoc_info!(
+        "Admin {} sets pinned = {} on history of {}",
+        req.claim.uuid,
+        req.body.pinned,
+        req.body.uuid
+    );
+    store::set_pinned(&req.body.uuid, req.body.pinned).await;
+    ok(())
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct TestBody {
     id: usize,
@@ -0,0 +1,138 @@
+/// Cost estimation of completions and budget alerts.
+use std::{collections::BTreeSet, sync::Mutex};
+
+use serde::Serialize;
+use serde_json::json;
+use sqlx::prelude::FromRow;
+
+use crate::{agent::TokenUsage, config::ModelPrice, indoc_warn, states::server_config};
+
+/// Alerts already fired, as `scope|day`.
+static FIRED_ALERTS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+pub fn estimate(price: Option<&ModelPrice>, usage: &TokenUsage) -> f64 {
+    let Some(price) = price else {
+        return 0.0;
+    };
+    (usage.prompt_tokens as f64 * price.input_per_1k
+        + usage.completion_tokens as f64 * price.output_per_1k)
+        / 1000.0
+}
+
+/// Dimension to aggregate cost by.
+#[derive(Debug, Clone, Copy)]
+pub enum CostGroup {
+    User,
+    Model,
+    Day,
+}
+
+/// Aggregated consumption of one user, model or day.
+#[derive(FromRow, Debug, Serialize)]
+pub struct CostBucket {
+    pub key: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub cost: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CostReport {
+    pub by_user: Vec<CostBucket>,
+    pub by_model: Vec<CostBucket>,
+    pub by_day: Vec<CostBucket>,
+}
+
+pub async fn report(days: u32) -> CostReport {
+    CostReport {
+        by_user: CostBucket::aggregate(CostGroup::User, days).await,
+        by_model: CostBucket::aggregate(CostGroup::Model, days).await,
+        by_day: CostBucket::aggregate(CostGroup::Day, days).await,
+    }
+}
+
+/// Compare today's spend with the thresholds, after usage of uuid is recorded.
+pub async fn check_budget(uuid: String) {
+    let config = server_config();
+    let budget = &config.budget;
+    if let Some(threshold) = budget.global_daily {
+        let spent = CostBucket::spent_today(None).await;
+        if spent >= threshold {
+            fire_alert("global".into(), spent, threshold).await;
+        }
+    }
+    if let Some(threshold) = budget.user_daily {
+        let spent = CostBucket::spent_today(Some(&uuid)).await;
+        if spent >= threshold {
+            fire_alert(format!("user:{uuid}"), spent, threshold).await;
+        }
+    }
+}
+
+/// Record the alert of scope on day, false if already fired.
+/// Alerts of past days are forgotten, so that the set holds one day at most.
+fn first_alert(fired: &mut BTreeSet<String>, scope: &str, day: &str) -> bool {
+    let suffix = format!("|{day}");
+    fired.retain(|key| key.ends_with(&suffix));
+    fired.insert(format!("{scope}{suffix}"))
+}
+
+async fn fire_alert(scope: String, spent: f64, threshold: f64) {
+    let day = time::OffsetDateTime::now_utc().date().to_string();
+    if !first_alert(&mut FIRED_ALERTS.lock().unwrap(), &scope, &day) {
+        return;
+    }
+    indoc_warn!(
+        "
+        Budget alert, {scope} spent ${spent:.4} on {day}, threshold ${threshold:.4}.
+        "
+    );
+    let config = server_config();
+    let Some(ref url) = config.budget.webhook_url else {
+        return;
+    };
+    let payload = json!({
+        "scope": scope,
+        "day": day,
+        "spent": spent,
+        "threshold": threshold,
+    });
+    let res = reqwest::Client::new().post(url).json(&payload).send().await;
+    if let Err(e) = res.and_then(|r| r.error_for_status()) {
+        indoc_warn!(
+            "
+            Budget alert webhook failed, error:
+            {e}
+            "
+        );
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimate_cost() {
+        let price = ModelPrice {
+            input_per_1k: 0.005,
+            output_per_1k: 0.015,
+        };
+        let usage = TokenUsage {
+            prompt_tokens: 2000,
+            completion_tokens: 1000,
+        };
+        assert!((estimate(Some(&price), &usage) - 0.025).abs() < 1e-9);
+        assert_eq!(estimate(None, &usage), 0.0);
+    }
+
+    #[test]
+    fn alerts_once_per_day() {
+        let mut fired = BTreeSet::new();
+        assert!(first_alert(&mut fired, "global", "2026-01-01"));
+        assert!(first_alert(&mut fired, "user:a", "2026-01-01"));
+        assert!(!first_alert(&mut fired, "global", "2026-01-01"));
+        assert!(first_alert(&mut fired, "global", "2026-01-02"));
+        assert_eq!(fired.len(), 1);
+    }
+}
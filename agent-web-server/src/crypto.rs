@@ -0,0 +1,272 @@
+/// Envelope encryption of message content at rest.
+///
+/// Every message is sealed with its own random data key using XChaCha20-Poly1305,
+/// the data key is sealed with the master key and stored next to the ciphertext:
+/// `enc1:<key id>:<base64 of wrap nonce | wrapped data key | nonce | ciphertext>`.
+/// The conversation uuid is bound as associated data, so rows can't be moved between users.
+use std::{
+    fmt::{self, Debug},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chacha20poly1305::{
+    Key, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::EncryptionConfig, indoc_info, store::Repository};
+
+const PREFIX: &str = "enc1";
+/// Takes precedence over the key file, base64 of 32 bytes.
+pub const MASTER_KEY_ENV: &str = "AGENT_WEB_MASTER_KEY";
+const NONCE_LEN: usize = 24;
+/// Data key plus tag.
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+
+pub struct MasterKey {
+    /// Short fingerprint stored with every row, to pick the key on decryption.
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterKey").field("id", &self.id).finish()
+    }
+}
+
+impl MasterKey {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() != 32 {
+            bail!("master key must be 32 bytes, got {}", bytes.len());
+        }
+        let digest = Sha256::digest(bytes);
+        let id = digest[..4].iter().map(|b| format!("{b:02x}")).collect();
+        Ok(Self {
+            id,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(bytes)),
+        })
+    }
+
+    /// Read key file, generate it if missing while no message is encrypted yet.
+    /// A missing key with encrypted messages is a wrong data directory or a lost file,
+    /// a fresh key would leave every message unreadable.
+    async fn load_or_generate(path: &Path, repo: &dyn Repository) -> anyhow::Result<Self> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        if repo.any_content_with_prefix(&format!("{PREFIX}:")).await? {
+            bail!(
+                "master key {} is missing but messages are encrypted, \
+                restore the key file or set {MASTER_KEY_ENV}",
+                path.display()
+            );
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("create key {}", path.display()))?;
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        file.write_all(&key).with_context(|| "write master key")?;
+        indoc_info!("Generated master key {}.", path.display());
+        Self::from_bytes(&key)
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("read key {}", path.display()))?;
+        Self::from_bytes(&bytes).with_context(|| format!("key {}", path.display()))
+    }
+}
+
+/// Current master key for encryption, and retired ones still able to decrypt.
+#[derive(Debug)]
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl Keyring {
+    pub fn new(current: MasterKey, previous: Vec<MasterKey>) -> Self {
+        Self { current, previous }
+    }
+
+    /// Key files are relative to data directory,
+    /// repo tells whether a missing key file may be generated.
+    pub async fn load(
+        config: &EncryptionConfig,
+        data_dir: &Path,
+        repo: &dyn Repository,
+    ) -> anyhow::Result<Self> {
+        let current = match std::env::var(MASTER_KEY_ENV) {
+            Ok(encoded) => {
+                let bytes = BASE64
+                    .decode(encoded.trim())
+                    .with_context(|| format!("decode {MASTER_KEY_ENV}"))?;
+                MasterKey::from_bytes(&bytes).with_context(|| MASTER_KEY_ENV)?
+            }
+            Err(_) => MasterKey::load_or_generate(&data_dir.join(&config.key_file), repo).await?,
+        };
+        let previous = config
+            .previous_key_files
+            .iter()
+            .map(|f| MasterKey::load(&data_dir.join(f)))
+            .collect::<anyhow::Result<_>>()?;
+        indoc_info!("Message encryption enabled, master key {}.", current.id);
+        Ok(Self::new(current, previous))
+    }
+
+    pub fn encrypt(&self, plaintext: &str, uuid: &str) -> anyhow::Result<String> {
+        let data_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let wrap_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped_key = self
+            .current
+            .cipher
+            .encrypt(&wrap_nonce, data_key.as_slice())
+            .map_err(|_| anyhow!("wrap data key"))?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: uuid.as_bytes(),
+        };
+        let ciphertext = XChaCha20Poly1305::new(&data_key)
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("encrypt message"))?;
+        let sealed = [&wrap_nonce[..], &wrapped_key, &nonce[..], &ciphertext].concat();
+        Ok(format!(
+            "{PREFIX}:{}:{}",
+            self.current.id,
+            BASE64.encode(sealed)
+        ))
+    }
+
+    /// Content without the envelope prefix is plaintext from before encryption and returned as is.
+    pub fn decrypt(&self, stored: &str, uuid: &str) -> anyhow::Result<String> {
+        let Some((key_id, sealed)) = split_envelope(stored) else {
+            return Ok(stored.to_string());
+        };
+        let key = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|k| k.id == key_id)
+            .with_context(|| format!("unknown master key {key_id}"))?;
+        let sealed = BASE64.decode(sealed).with_context(|| "decode envelope")?;
+        if sealed.len() < 2 * NONCE_LEN + WRAPPED_KEY_LEN {
+            bail!("envelope too short");
+        }
+        let (wrap_nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let data_key = key
+            .cipher
+            .decrypt(XNonce::from_slice(wrap_nonce), wrapped_key)
+            .map_err(|_| anyhow!("unwrap data key with master key {key_id}"))?;
+        let payload = Payload {
+            msg: ciphertext,
+            aad: uuid.as_bytes(),
+        };
+        let plaintext = XChaCha20Poly1305::new(Key::from_slice(&data_key))
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("decrypt message"))?;
+        String::from_utf8(plaintext).with_context(|| "decrypted message is not UTF-8")
+    }
+
+    /// Whether stored content is sealed by the current master key, i.e. needs no rotation.
+    pub fn is_current(&self, stored: &str) -> bool {
+        split_envelope(stored).is_some_and(|(key_id, _)| key_id == self.current.id)
+    }
+}
+
+fn split_envelope(stored: &str) -> Option<(&str, &str)> {
+    let rest = stored.strip_prefix(PREFIX)?.strip_prefix(':')?;
+    rest.split_once(':')
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let keyring = Keyring::new(key(1), Vec::new());
+        let sealed = keyring.encrypt("héllo", "u1").unwrap();
+        assert!(sealed.starts_with("enc1:"));
+        assert!(!sealed.contains("llo"));
+        assert!(keyring.is_current(&sealed));
+        assert_eq!(keyring.decrypt(&sealed, "u1").unwrap(), "héllo");
+        // fresh data key and nonces every time
+        assert_ne!(keyring.encrypt("héllo", "u1").unwrap(), sealed);
+        // bound to the conversation
+        assert!(keyring.decrypt(&sealed, "u2").is_err());
+        // plaintext from before encryption passes through
+        assert_eq!(keyring.decrypt("plain", "u1").unwrap(), "plain");
+        assert!(!keyring.is_current("plain"));
+    }
+
+    #[test]
+    fn rotate_master_key() {
+        let old = Keyring::new(key(1), Vec::new());
+        let sealed = old.encrypt("secret", "u").unwrap();
+
+        let rotated = Keyring::new(key(2), vec![key(1)]);
+        assert!(!rotated.is_current(&sealed));
+        let plaintext = rotated.decrypt(&sealed, "u").unwrap();
+        let resealed = rotated.encrypt(&plaintext, "u").unwrap();
+        assert!(rotated.is_current(&resealed));
+
+        let retired = Keyring::new(key(2), Vec::new());
+        assert!(retired.decrypt(&sealed, "u").is_err());
+        assert_eq!(retired.decrypt(&resealed, "u").unwrap(), "secret");
+    }
+
+    #[test]
+    fn reject_tampering() {
+        let keyring = Keyring::new(key(1), Vec::new());
+        let sealed = keyring.encrypt("secret", "u").unwrap();
+        let (head, body) = sealed.rsplit_once(':').unwrap();
+        let mut bytes = BASE64.decode(body).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        let tampered = format!("{head}:{}", BASE64.encode(bytes));
+        assert!(keyring.decrypt(&tampered, "u").is_err());
+        assert!(MasterKey::from_bytes(&[0; 16]).is_err());
+    }
+
+    #[tokio::test]
+    async fn generate_only_without_ciphertext() {
+        let dir = std::env::temp_dir().join(format!("agent-web-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("master.key");
+        let repo = crate::store::test::memory_repository().await;
+        // no schema yet on first start
+        let generated = MasterKey::load_or_generate(&path, &repo).await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = MasterKey::load_or_generate(&path, &repo).await.unwrap();
+        assert_eq!(loaded.id, generated.id);
+
+        repo.migrate().await.unwrap();
+        let keyring = Keyring::new(generated, Vec::new());
+        let mut message = crate::agent::ChatMessage::create_user("u", "");
+        message.content = keyring.encrypt("secret", "u").unwrap();
+        repo.persist_message(&message).await.unwrap();
+        fs::remove_file(&path).unwrap();
+        let err = MasterKey::load_or_generate(&path, &repo).await.unwrap_err();
+        assert!(err.to_string().contains("messages are encrypted"), "{err}");
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
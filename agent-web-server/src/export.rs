@@ -0,0 +1,353 @@
+/// Conversation export to JSON, Markdown and OpenAI fine-tuning JSONL, import from JSON.
+use std::str::FromStr;
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use strum::{Display, EnumString};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+use crate::{
+    agent::{ChatMessage, MessageRole, MessageStatus, TIME_FORMAT},
+    auth::Role,
+    states::server_config,
+    store::{self, Repository},
+};
+
+/// Bumped on incompatible changes of [ConversationExport].
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Imported times may be ahead of the server clock by this much.
+/// Later ones are rejected, they would keep the conversation from ever expiring.
+const MAX_CLOCK_SKEW: Duration = Duration::minutes(5);
+
+#[derive(EnumString, Display, Deserialize, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Structured, the only format that can be imported.
+    Json,
+    Markdown,
+    /// OpenAI fine-tuning format, one conversation per line.
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Markdown => "md",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// One conversation, i.e. the whole history of a uuid.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConversationExport {
+    pub version: u32,
+    pub uuid: String,
+    /// Prompt in effect at export time, it is not stored per conversation.
+    pub system_prompt: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+/// Import accepts a single conversation or a list of them, as exported by the CLI.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ImportDocument {
+    One(ConversationExport),
+    Many(Vec<ConversationExport>),
+}
+
+impl ImportDocument {
+    pub fn into_vec(self) -> Vec<ConversationExport> {
+        match self {
+            Self::One(c) => vec![c],
+            Self::Many(list) => list,
+        }
+    }
+}
+
+pub async fn load(
+    repo: &dyn Repository,
+    uuid: &str,
+    system_prompt: &str,
+) -> anyhow::Result<ConversationExport> {
+    Ok(ConversationExport {
+        version: EXPORT_VERSION,
+        uuid: uuid.to_string(),
+        system_prompt: system_prompt.to_string(),
+        messages: repo.load_history(uuid).await?,
+    })
+}
+
+/// Render conversations into one document.
+pub fn render(
+    conversations: &[ConversationExport],
+    format: ExportFormat,
+) -> anyhow::Result<String> {
+    match format {
+        ExportFormat::Json => {
+            let doc = match conversations {
+                [one] => serde_json::to_string_pretty(one),
+                many => serde_json::to_string_pretty(many),
+            };
+            Ok(doc?)
+        }
+        ExportFormat::Markdown => Ok(conversations
+            .iter()
+            .map(to_markdown)
+            .collect::<Vec<_>>()
+            .join("\n---\n\n")),
+        ExportFormat::Jsonl => Ok(conversations
+            .iter()
+            .filter(|c| !c.messages.is_empty())
+            .map(to_finetune_line)
+            .collect::<Vec<_>>()
+            .join("")),
+    }
+}
+
+fn to_markdown(conversation: &ConversationExport) -> String {
+    let mut doc = format!(
+        "# Conversation {}\n\n## System\n\n{}\n\n",
+        conversation.uuid, conversation.system_prompt
+    );
+    for m in &conversation.messages {
+        doc.push_str(&format!("## {} ({} UTC)\n\n", m.role, m.time));
+        match m.get_status() {
+            MessageStatus::Ok => doc.push_str(&format!("{}\n\n", m.content)),
+            status => doc.push_str(&format!("*No answer, {status}.*\n\n")),
+        }
+        if let Some(ref model) = m.model {
+            doc.push_str(&format!(
+                "*{model}, {} prompt + {} completion tokens*\n\n",
+                m.usage.prompt_tokens, m.usage.completion_tokens
+            ));
+        }
+    }
+    doc
+}
+
+/// Failed answers are left out, they carry no content to learn from.
+fn to_finetune_line(conversation: &ConversationExport) -> String {
+    let system = json!({ "role": "system", "content": conversation.system_prompt });
+    let messages: Vec<_> = std::iter::once(system)
+        .chain(
+            conversation
+                .messages
+                .iter()
+                .filter(|m| m.get_status() == MessageStatus::Ok)
+                .map(|m| json!({ "role": m.role.to_lowercase(), "content": m.content })),
+        )
+        .collect();
+    format!("{}\n", json!({ "messages": messages }))
+}
+
+/// Recreate a conversation under uuid, which must have no history yet.
+/// Returns the number of messages imported.
+pub async fn import(
+    repo: &dyn Repository,
+    conversation: &ConversationExport,
+    uuid: &str,
+) -> anyhow::Result<usize> {
+    if conversation.version > EXPORT_VERSION {
+        bail!("unsupported export version {}", conversation.version);
+    }
+    let latest = OffsetDateTime::now_utc() + MAX_CLOCK_SKEW;
+    let latest = PrimitiveDateTime::new(latest.date(), latest.time());
+    for (i, m) in conversation.messages.iter().enumerate() {
+        MessageRole::from_str(&m.role).with_context(|| format!("message {i}: role {}", m.role))?;
+        MessageStatus::from_str(&m.status)
+            .with_context(|| format!("message {i}: status {}", m.status))?;
+        let time = PrimitiveDateTime::parse(&m.time, TIME_FORMAT)
+            .with_context(|| format!("message {i}: time {}", m.time))?;
+        if time > latest {
+            bail!("message {i}: time {} is in the future", m.time);
+        }
+    }
+    if !repo.load_history(uuid).await?.is_empty() {
+        bail!("conversation {uuid} already has messages, clear it first");
+    }
+    let messages: Vec<ChatMessage> = conversation
+        .messages
+        .iter()
+        .map(|m| ChatMessage {
+            uuid: uuid.to_string(),
+            ..m.clone()
+        })
+        .collect();
+    repo.import_messages(&messages).await?;
+    Ok(messages.len())
+}
+
+/// Run the `export` subcommand, every conversation if uuid is absent.
+pub async fn export_command(
+    uuid: Option<String>,
+    format: ExportFormat,
+    output: Option<std::path::PathBuf>,
+) -> anyhow::Result<()> {
+    let repo = store::repo();
+    let config = server_config();
+    let uuids = match uuid {
+        Some(uuid) => vec![uuid],
+        None => repo.list_conversations().await?,
+    };
+    let mut conversations = Vec::new();
+    for uuid in &uuids {
+        conversations.push(load(repo, uuid, &config.sys_prompt).await?);
+    }
+    let doc = render(&conversations, format)?;
+    match output {
+        Some(path) => {
+            std::fs::write(&path, doc).with_context(|| format!("write {}", path.display()))?
+        }
+        None => print!("{doc}"),
+    }
+    Ok(())
+}
+
+/// Run the `import` subcommand, conversations keep their uuid unless overridden.
+pub async fn import_command(file: std::path::PathBuf, uuid: Option<String>) -> anyhow::Result<()> {
+    let repo = store::repo();
+    let doc = std::fs::read_to_string(&file).with_context(|| format!("read {}", file.display()))?;
+    let conversations = serde_json::from_str::<ImportDocument>(&doc)
+        .with_context(|| "parse export, only the JSON format can be imported")?
+        .into_vec();
+    if uuid.is_some() && conversations.len() > 1 {
+        bail!("--uuid needs a file with a single conversation");
+    }
+    for conversation in &conversations {
+        let uuid = uuid.as_deref().unwrap_or(&conversation.uuid);
+        if repo.find_user(uuid).await?.is_none() {
+            repo.create_user(uuid, Role::User)
+                .await
+                .with_context(|| format!("create user {uuid}"))?;
+        }
+        let count = import(repo, conversation, uuid).await?;
+        println!("{uuid}: {count} messages imported");
+    }
+    Ok(())
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+    use crate::{
+        agent::{AgentReply, TokenUsage},
+        store::test::memory_repository,
+    };
+
+    async fn sample(repo: &dyn Repository, uuid: &str) {
+        let reply = AgentReply {
+            content: "use `\\d+`".into(),
+            usage: TokenUsage {
+                prompt_tokens: 12,
+                completion_tokens: 4,
+            },
+            model: "model-a".into(),
+            latency_ms: 321,
+            finish_reason: Some("stop".into()),
+        };
+        let messages = [
+            ChatMessage::create_user(uuid, "regex for digits?\nthanks \"quoted\""),
+            ChatMessage::create_assistant(uuid, &reply),
+            ChatMessage::create_user(uuid, "again"),
+            ChatMessage::create_failed(uuid, "model-a", 9, MessageStatus::Error),
+        ];
+        for m in &messages {
+            repo.persist_message(m).await.unwrap();
+        }
+    }
+
+    /// Everything but row ids and the uuid survives export followed by import.
+    #[tokio::test]
+    async fn json_round_trip() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        sample(&repo, "a").await;
+
+        let exported = load(&repo, "a", "be brief").await.unwrap();
+        let doc = render(std::slice::from_ref(&exported), ExportFormat::Json).unwrap();
+        let parsed = serde_json::from_str::<ImportDocument>(&doc)
+            .unwrap()
+            .into_vec();
+        let strip = |c: &ConversationExport| -> Vec<ChatMessage> {
+            c.messages
+                .iter()
+                .map(|m| ChatMessage {
+                    id: 0,
+                    uuid: String::new(),
+                    ..m.clone()
+                })
+                .collect()
+        };
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].system_prompt, "be brief");
+        assert_eq!(strip(&parsed[0]), strip(&exported));
+
+        assert_eq!(import(&repo, &parsed[0], "b").await.unwrap(), 4);
+        let imported = load(&repo, "b", "be brief").await.unwrap();
+        assert_eq!(strip(&imported), strip(&exported));
+
+        // a second export of the copy is identical apart from ids and uuid
+        let again = render(&[imported], ExportFormat::Json).unwrap();
+        let again: ConversationExport = serde_json::from_str(&again).unwrap();
+        assert_eq!(strip(&again), strip(&exported));
+
+        // timestamps are kept, not reset to the import time
+        let mut old = parsed[0].clone();
+        old.messages[0].time = "2024-01-02 03:04:05".into();
+        import(&repo, &old, "c").await.unwrap();
+        let history = repo.load_history("c").await.unwrap();
+        assert_eq!(history[0].time, "2024-01-02 03:04:05");
+
+        // never merged into an existing conversation
+        assert!(import(&repo, &parsed[0], "b").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_import() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        sample(&repo, "a").await;
+        let mut exported = load(&repo, "a", "").await.unwrap();
+        let mut future = exported.clone();
+        exported.messages[0].role = "System".into();
+        assert!(import(&repo, &exported, "b").await.is_err());
+        assert!(repo.load_history("b").await.unwrap().is_empty());
+
+        // a future time would exempt the conversation from retention
+        future.messages[1].time = "9999-12-31 23:59:59".into();
+        let err = import(&repo, &future, "b").await.unwrap_err();
+        assert!(err.to_string().contains("in the future"), "{err}");
+        assert!(repo.load_history("b").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finetune_and_markdown() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        sample(&repo, "a").await;
+        let exported = load(&repo, "a", "be brief").await.unwrap();
+
+        let jsonl = render(std::slice::from_ref(&exported), ExportFormat::Jsonl).unwrap();
+        assert_eq!(jsonl.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(jsonl.trim_end()).unwrap();
+        let roles: Vec<_> = line["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        // the failed answer is left out
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(line["messages"][0]["content"], "be brief");
+
+        let markdown = render(&[exported], ExportFormat::Markdown).unwrap();
+        assert!(markdown.starts_with("# Conversation a\n\n## System\n\nbe brief\n"));
+        assert!(markdown.contains("use `\\d+`"));
+        assert!(markdown.contains("*No answer, error.*"));
+    }
+}
@@ -0,0 +1,223 @@
+/// Probes for load balancers and orchestrators: liveness, readiness and build info.
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use axum::{Json, http::StatusCode};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::ServerConfig,
+    indoc_warn, reload,
+    shutdown::SHUTDOWN,
+    states::{agent_client, server_config},
+    store::{self, Repository},
+};
+
+/// A check taking longer counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ordered from best to worst.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Serving, with some feature impaired.
+    Degraded,
+    /// Send no traffic here.
+    Unavailable,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Self {
+            status: Status::Ok,
+            latency_ms: None,
+            detail: None,
+        }
+    }
+
+    /// Detail is served on a public probe, errors themselves belong in the log.
+    fn failed(status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            latency_ms: None,
+            detail: Some(detail.into()),
+        }
+    }
+
+    fn timed(mut self, started: Instant) -> Self {
+        self.latency_ms = Some(started.elapsed().as_millis() as u64);
+        self
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    /// Worst of the checks.
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Version {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub rustc: &'static str,
+    pub target: &'static str,
+    pub profile: &'static str,
+}
+
+pub const VERSION: Version = Version {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    git_commit: env!("AGENT_WEB_GIT_COMMIT"),
+    rustc: env!("AGENT_WEB_RUSTC"),
+    target: env!("AGENT_WEB_TARGET"),
+    profile: env!("AGENT_WEB_PROFILE"),
+};
+
+async fn check_database(repo: &dyn Repository) -> Check {
+    let started = Instant::now();
+    let check = match tokio::time::timeout(CHECK_TIMEOUT, repo.ping()).await {
+        Ok(Ok(())) => Check::ok(),
+        Ok(Err(e)) => {
+            indoc_warn!(
+                "
+                Readiness database check failed, error:
+                {e:#}
+                "
+            );
+            Check::failed(Status::Unavailable, "query failed, see logs")
+        }
+        Err(_) => Check::failed(Status::Unavailable, "no answer in time"),
+    };
+    check.timed(started)
+}
+
+/// The running config was validated on load, only a failed reload is reported.
+fn check_config(reload_failed: bool) -> Check {
+    match reload_failed {
+        true => Check::failed(
+            Status::Degraded,
+            "latest reload failed, previous config running, see logs",
+        ),
+        false => Check::ok(),
+    }
+}
+
+/// Latest ping of the provider, reused while fresh.
+static UPSTREAM: Mutex<Option<(Instant, Check)>> = Mutex::const_new(None);
+
+async fn check_upstream(config: &ServerConfig) -> Check {
+    // held across the ping, so that concurrent probes wait for one result
+    let mut cached = UPSTREAM.lock().await;
+    let max_age = Duration::from_secs(config.health.upstream_cache_secs);
+    if let Some((at, check)) = cached.as_ref()
+        && at.elapsed() < max_age
+    {
+        return check.clone();
+    }
+    let started = Instant::now();
+    let check = match tokio::time::timeout(CHECK_TIMEOUT, agent_client().models().list()).await {
+        Ok(Ok(_)) => Check::ok(),
+        Ok(Err(e)) => {
+            indoc_warn!(
+                "
+                Readiness upstream check failed, error:
+                {e}
+                "
+            );
+            Check::failed(Status::Degraded, "provider error, see logs")
+        }
+        Err(_) => Check::failed(Status::Degraded, "no answer in time"),
+    }
+    .timed(started);
+    *cached = Some((Instant::now(), check.clone()));
+    check
+}
+
+pub async fn readiness(repo: &dyn Repository, config: &ServerConfig) -> Readiness {
+    let mut checks = BTreeMap::new();
+    checks.insert("database", check_database(repo).await);
+    checks.insert("config", check_config(reload::last_failed()));
+    if config.health.upstream_ping {
+        checks.insert("upstream", check_upstream(config).await);
+    }
+    if SHUTDOWN.is_requested() {
+        let detail = format!(
+            "shutting down, {} generations draining",
+            SHUTDOWN.active_generations()
+        );
+        checks.insert("server", Check::failed(Status::Unavailable, detail));
+    }
+    let status = checks
+        .values()
+        .map(|c| c.status)
+        .max()
+        .unwrap_or(Status::Ok);
+    Readiness { status, checks }
+}
+
+/// Liveness, answers as long as the server accepts requests.
+pub async fn healthz() -> Json<Check> {
+    Json(Check::ok())
+}
+
+/// Readiness, 503 if unavailable, 200 otherwise even if degraded.
+pub async fn readyz() -> (StatusCode, Json<Readiness>) {
+    let readiness = readiness(store::repo(), &server_config()).await;
+    let code = match readiness.status {
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Status::Ok | Status::Degraded => StatusCode::OK,
+    };
+    (code, Json(readiness))
+}
+
+pub async fn version() -> Json<Version> {
+    Json(VERSION)
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+    use crate::store::test::memory_repository;
+
+    #[tokio::test]
+    async fn readiness_reports_worst_check() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        let config = ServerConfig {
+            api_key: "sk-test".into(),
+            ..Default::default()
+        };
+
+        let ready = readiness(&repo, &config).await;
+        assert_eq!(ready.status, Status::Ok);
+        assert!(ready.checks["database"].latency_ms.is_some());
+        assert!(!ready.checks.contains_key("upstream"));
+        let json = serde_json::to_value(&ready).unwrap();
+        assert_eq!(json["status"], "ok");
+        assert_eq!(
+            json["checks"]["config"],
+            serde_json::json!({"status": "ok"})
+        );
+
+        // the error may quote secrets, the public probe only points to the logs
+        let stale = check_config(true);
+        assert_eq!(stale.status, Status::Degraded);
+        assert!(stale.detail.unwrap().ends_with("see logs"));
+    }
+}
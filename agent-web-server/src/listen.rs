@@ -0,0 +1,389 @@
+/// Where the server accepts connections: TCP on a configured address, optionally with TLS,
+/// or a Unix socket behind a local reverse proxy.
+use std::{
+    fmt::Debug,
+    future::IntoFuture,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use axum::{
+    Router,
+    extract::connect_info::Connected,
+    serve::{IncomingStream, Listener},
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::{
+    config::ListenConfig, indoc_debug, indoc_info, indoc_warn, shutdown::SHUTDOWN,
+    states::server_config,
+};
+
+/// How often certificate files are checked for change.
+const CERT_POLL: Duration = Duration::from_secs(10);
+/// Clients not done with the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peer of a connection, absent over a Unix socket.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub Option<SocketAddr>);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(Some(*stream.remote_addr()))
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self(Some(*stream.remote_addr()))
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, tokio::net::UnixListener>> for ClientAddr {
+    fn connect_info(_: IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+        Self(None)
+    }
+}
+
+/// Serves the current certificate, swapped on reload without dropping connections.
+#[derive(Debug)]
+pub struct CertResolver {
+    key: ArcSwap<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn new(key: CertifiedKey) -> Self {
+        Self {
+            key: ArcSwap::from_pointee(key),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.load_full())
+    }
+}
+
+/// Certificate chain and private key from PEM files, checked to match.
+pub fn load_certified_key(cert: &Path, key: &Path) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("read certificate {}", cert.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificate in {}", cert.display());
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("read private key {}", key.display()))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&private_key)
+        .with_context(|| format!("unsupported private key {}", key.display()))?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified
+        .keys_match()
+        .with_context(|| format!("{} does not match {}", cert.display(), key.display()))?;
+    Ok(certified)
+}
+
+pub fn tls_acceptor(resolver: Arc<CertResolver>) -> anyhow::Result<TlsAcceptor> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    // axum is built for HTTP/1 only
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reload the certificate when either file changes, the current one stays on error.
+/// Modification times are polled rather than watched, as certificates are often symlinks
+/// swapped by the renewing tool.
+pub async fn block_watch_cert(
+    resolver: Arc<CertResolver>,
+    cert: PathBuf,
+    key: PathBuf,
+    every: Duration,
+) {
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    let mut last = (modified(&cert), modified(&key));
+    let mut interval = tokio::time::interval(every);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = SHUTDOWN.requested() => break,
+        }
+        let current = (modified(&cert), modified(&key));
+        if current == last {
+            continue;
+        }
+        last = current;
+        match load_certified_key(&cert, &key) {
+            Ok(certified) => {
+                resolver.key.store(Arc::new(certified));
+                indoc_info!("TLS certificate reloaded from {}.", cert.display());
+            }
+            Err(e) => indoc_warn!(
+                "
+                TLS certificate reload failed, current one kept, error:
+                {e:#}
+                "
+            ),
+        }
+    }
+}
+
+/// TCP listener yielding connections once their TLS handshake is done.
+pub struct TlsListener {
+    rx: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(mut tcp: TcpListener, acceptor: TlsAcceptor) -> io::Result<Self> {
+        let local_addr = tcp.local_addr()?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = Listener::accept(&mut tcp) => accepted,
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                // each handshake on its own, a slow client holds up no other
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => indoc_debug!("TLS handshake with {addr} failed: {e}"),
+                        Err(_) => indoc_debug!("TLS handshake with {addr} timed out."),
+                    }
+                });
+            }
+        });
+        Ok(Self { rx, local_addr })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(accepted) => accepted,
+            // the accept loop never ends while this listener lives
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+pub async fn serve_on<L>(listener: L, app: Router) -> anyhow::Result<()>
+where
+    L: Listener,
+    L::Addr: Debug,
+    ClientAddr: for<'a> Connected<IncomingStream<'a, L>>,
+{
+    let serving = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<ClientAddr>(),
+    )
+    .with_graceful_shutdown(SHUTDOWN.requested())
+    .into_future();
+    SHUTDOWN
+        .drain(serving, || {
+            Duration::from_secs(server_config().shutdown_deadline_secs)
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // left by a previous run, binding fails on an existing file
+    if let Ok(metadata) = path.symlink_metadata() {
+        anyhow::ensure!(
+            metadata.file_type().is_socket(),
+            "{} exists and is not a socket",
+            path.display()
+        );
+        std::fs::remove_file(path).with_context(|| "remove stale unix socket")?;
+    }
+    tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("listen on unix socket {}", path.display()))
+}
+
+/// Serve `app` as configured, paths of `listen` relative to `data_dir`.
+/// `port` is required unless serving on a Unix socket.
+pub async fn serve(
+    app: Router,
+    port: Option<u16>,
+    listen: &ListenConfig,
+    data_dir: &Path,
+) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if let Some(socket) = &listen.unix_socket {
+        let path = data_dir.join(socket);
+        let listener = bind_unix_socket(&path)?;
+        indoc_info!("Server listening on unix socket {}...", path.display());
+        serve_on(listener, app).await?;
+        std::fs::remove_file(&path).with_context(|| "remove unix socket")?;
+        return Ok(());
+    }
+    let port = port.with_context(|| "port is required to serve over TCP, give `--port`")?;
+    let ip: IpAddr = listen
+        .address
+        .parse()
+        .with_context(|| format!("listen address {}", listen.address))?;
+    let addr = SocketAddr::new(ip, port);
+    let tcp = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("tcp listen on {addr}"))?;
+    let Some(tls) = &listen.tls else {
+        indoc_info!("Server listening on http://{addr}...");
+        return serve_on(tcp, app).await;
+    };
+    let cert = data_dir.join(&tls.cert_file);
+    let key = data_dir.join(&tls.key_file);
+    let resolver = Arc::new(CertResolver::new(load_certified_key(&cert, &key)?));
+    let listener = TlsListener::new(tcp, tls_acceptor(resolver.clone())?)?;
+    tokio::spawn(block_watch_cert(resolver, cert, key, CERT_POLL));
+    indoc_info!("Server listening on https://{addr}...");
+    serve_on(listener, app).await
+}
+
+#[allow(unused)]
+mod test {
+    use std::fs;
+
+    use axum::{extract::ConnectInfo, routing::get};
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-web-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn peer_app() -> Router {
+        Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<ClientAddr>| async move {
+                format!("{:?}", peer.0.map(|a| a.ip()))
+            }),
+        )
+    }
+
+    /// Self-signed for `localhost`, written to the files, returns the certificate PEM.
+    // rcgen is a dev-dependency only
+    #[cfg(test)]
+    fn write_self_signed(cert: &Path, key: &Path) -> String {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let pem = generated.cert.pem();
+        fs::write(cert, &pem).unwrap();
+        fs::write(key, generated.key_pair.serialize_pem()).unwrap();
+        pem
+    }
+
+    async fn get_trusting(pem: &str, addr: SocketAddr) -> reqwest::Result<String> {
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(pem.as_bytes())?)
+            .resolve("localhost", addr)
+            .build()?;
+        client
+            .get(format!("https://localhost:{}/", addr.port()))
+            .send()
+            .await?
+            .text()
+            .await
+    }
+
+    #[cfg(test)]
+    #[tokio::test]
+    async fn tls_with_reload() {
+        let dir = temp_dir();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let old_pem = write_self_signed(&cert, &key);
+        let resolver = Arc::new(CertResolver::new(load_certified_key(&cert, &key).unwrap()));
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(tcp, tls_acceptor(resolver.clone()).unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(block_watch_cert(
+            resolver,
+            cert.clone(),
+            key.clone(),
+            Duration::from_millis(50),
+        ));
+        tokio::spawn(serve_on(listener, peer_app()));
+
+        assert_eq!(
+            get_trusting(&old_pem, addr).await.unwrap(),
+            "Some(127.0.0.1)"
+        );
+
+        // a key not matching the certificate is refused, the old pair stays
+        let other = rcgen::KeyPair::generate().unwrap();
+        fs::write(&key, other.serialize_pem()).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(get_trusting(&old_pem, addr).await.is_ok());
+
+        let new_pem = write_self_signed(&cert, &key);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(get_trusting(&new_pem, addr).await.is_ok());
+        assert!(get_trusting(&old_pem, addr).await.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = temp_dir();
+        let listen = ListenConfig {
+            unix_socket: Some("agent.sock".into()),
+            ..Default::default()
+        };
+        // a socket left by a previous run is replaced
+        drop(std::os::unix::net::UnixListener::bind(dir.join("agent.sock")).unwrap());
+        let server_dir = dir.clone();
+        tokio::spawn(async move { serve(peer_app(), None, &listen, &server_dir).await });
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(dir.join("agent.sock")).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("None"), "{response}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,33 +1,163 @@
 mod agent;
 mod auth;
+mod backup;
 mod config;
 mod controller;
+mod cost;
+mod crypto;
+mod export;
+mod health;
+mod listen;
+mod metrics;
 mod protocol;
+mod ratelimit;
+mod reload;
+mod retention;
+mod search;
+mod shutdown;
 mod states;
 mod store;
+mod telemetry;
 mod tracing;
+mod usage;
+mod validate;
 
-use std::net::SocketAddr;
+use std::{path::PathBuf, time::Duration};
 
-use anyhow::{Context, Result};
-use axum::{Router, routing::post};
-use clap::Parser;
-use controller::{ask_agent, clear_history, fetch_history, init_session, test_auth};
-use states::COMMAND_LINE_ARGS;
+use anyhow::Result;
+use axum::{
+    Router, middleware,
+    routing::{get, post},
+};
+use clap::{Parser, Subcommand};
+use controller::{
+    admin_backup, admin_clear_history, admin_cost, admin_export_history, admin_fetch_history,
+    admin_list_users, admin_pin_history, admin_purge, admin_purge_runs, admin_restore_history,
+    ask_agent, clear_history, create_api_key, export_history, fetch_history, fetch_usage,
+    import_history, init_session, list_api_keys, restore_history, revoke_api_key, search_history,
+    test_auth,
+};
+use export::ExportFormat;
+use shutdown::SHUTDOWN;
 use tower_http::cors::CorsLayer;
 
 #[derive(Parser, Debug)]
 struct CommandLineArgs {
+    /// Port to serve on without subcommand, same as `serve --port`.
     #[arg(short = 'p', long = "port")]
-    port: usize,
-    #[arg(short = 'd', long = "debug")]
+    port: Option<u16>,
+    #[arg(short = 'd', long = "debug", global = true)]
     debug: bool,
+    /// Directory of config, database and keys, `server_data` next to the executable if absent.
+    #[arg(long, env = "AGENT_WEB_DATA_DIR", global = true)]
+    data_dir: Option<PathBuf>,
+    /// Override a config field, e.g. `--set retention.trash_days=30`, repeatable.
+    /// Takes precedence over `AGENT_WEB_*` variables and config.toml.
+    #[arg(long, value_name = "KEY=VALUE", global = true)]
+    set: Vec<String>,
+    /// Serve HTTP if absent.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Serve HTTP.
+    Serve {
+        /// Port to serve on, not needed on a Unix socket.
+        #[arg(short = 'p', long = "port")]
+        port: Option<u16>,
+    },
+    /// Write a config template to config.toml in data directory.
+    InitConfig {
+        /// Overwrite an existing config.toml.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Validate config.toml with environment and `--set` overrides, then exit.
+    CheckConfig,
+    /// Manage database schema.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// Export conversations.
+    Export {
+        /// Conversation to export, every conversation if absent.
+        #[arg(long)]
+        uuid: Option<String>,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Write to file instead of stdout.
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
+    },
+    /// Import conversations from a JSON export.
+    Import {
+        file: PathBuf,
+        /// Import into this uuid instead of the exported one.
+        #[arg(long)]
+        uuid: Option<String>,
+    },
+    /// Back up database, config and keys into the backup directory.
+    Backup,
+    /// Replace database, config and keys by a backup, the server must be stopped.
+    Restore { archive: PathBuf },
+    /// Manage users.
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+    /// Purge expired conversations and trash as the retention job does.
+    Purge {
+        /// Only report what would be purged.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Re-encrypt messages under the current master key.
+    RotateKey {
+        /// Messages rewritten per transaction.
+        #[arg(long, default_value_t = 500)]
+        batch_size: u32,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum UserAction {
+    /// Create a user and print its token.
+    Add {
+        #[arg(long, default_value_t = auth::Role::User)]
+        role: auth::Role,
+    },
+    /// List users with their role and status.
+    List,
+    /// Reject every token and API key of the user, history is kept.
+    Disable { uuid: String },
+    /// Undo `disable`.
+    Enable { uuid: String },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum MigrateAction {
+    /// List migrations and whether they are applied.
+    Status,
+    /// Apply all pending migrations.
+    Up,
+    /// Revert migrations, one step back by default.
+    Down {
+        /// Revert every migration newer than this version, 0 for all.
+        #[arg(long)]
+        target: Option<i64>,
+    },
 }
 
 fn main() -> Result<()> {
     // --- sync part ---
     let cli = CommandLineArgs::parse();
-    let _guard = tracing::init_tracing(cli.debug);
+    states::init_data_dir(cli.data_dir.as_deref())?;
+    // on defaults if config does not load, the command then reports why
+    let config = config::load_config(&cli.set).unwrap_or_default();
+    let guard = tracing::init_tracing(cli.debug, &config);
 
     indoc_info!("Tracing init completes.");
     indoc_info!(
@@ -39,48 +169,133 @@ fn main() -> Result<()> {
     );
 
     // --- async part ---
+    let command = cli.command.clone();
     let runtime = tokio::runtime::Runtime::new().unwrap();
-    runtime.block_on(async {
+    let res = runtime.block_on(async {
         indoc_info!("Async runtime starts.");
-        let init_res = states::init_states(cli).await;
-        store::init_chat_history_table().await;
-        tokio::spawn(async {
-            store::block_periodic_clear_history().await;
-        });
-        let service_res = root_future().await;
-
-        match init_res.and(service_res) {
-            Ok(_) => (),
-            Err(e) => {
-                indoc_error!(
-                    "
-                    Can't recover from error:
-                    {}
-                    Process aborts.
-                    ",
-                    e
-                );
+        let res = async {
+            // commands not touching the database
+            match command {
+                Some(Command::Restore { ref archive }) => {
+                    // the database must not be opened, its files get replaced
+                    return backup::restore_command(archive).await;
+                }
+                Some(Command::InitConfig { force }) => {
+                    return config::init_config_command(force);
+                }
+                Some(Command::CheckConfig) => {
+                    return config::check_config_command(&cli.set);
+                }
+                _ => (),
             }
+            let port = cli.port;
+            states::init_states(cli).await?;
+            // every other command works on the current schema, as serving does
+            if !matches!(command, Some(Command::Migrate { .. })) {
+                store::migrate().await?;
+            }
+            match command {
+                None => serve(port).await,
+                Some(Command::Serve { port }) => serve(port).await,
+                Some(Command::Migrate { action }) => store::migrate_command(action).await,
+                Some(Command::Export {
+                    uuid,
+                    format,
+                    output,
+                }) => export::export_command(uuid, format, output).await,
+                Some(Command::Import { file, uuid }) => export::import_command(file, uuid).await,
+                Some(Command::Backup) => backup::backup_command().await,
+                Some(Command::User { action }) => auth::user_command(action).await,
+                Some(Command::Purge { dry_run }) => retention::purge_command(dry_run).await,
+                Some(
+                    Command::Restore { .. } | Command::InitConfig { .. } | Command::CheckConfig,
+                ) => {
+                    unreachable!("run above")
+                }
+                Some(Command::RotateKey { batch_size }) => {
+                    store::encrypted::rotate_key_command(batch_size).await
+                }
+            }
+        }
+        .await;
+        if let Err(e) = &res {
+            // not indoc_error!, exiting right away would lose buffered logs
+            let content = indoc::formatdoc!(
+                "
+                Can't recover from error:
+                {:#}
+                Process aborts.
+                ",
+                e
+            );
+            ::tracing::error!("{}", content);
         }
+        res
     });
+    // tasks left, e.g. connections cut off by shutdown, are dropped
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    // flush logs before exit
+    drop(guard);
+    if res.is_err() {
+        std::process::exit(1);
+    }
     Ok(())
 }
 
-async fn root_future() -> Result<()> {
-    let cli_args = COMMAND_LINE_ARGS.get().unwrap();
-    let port = cli_args.port;
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await
-        .with_context(|| "tcp listen port")?;
-    indoc_info!("Server listening to port {}...", port);
+async fn serve(port: Option<u16>) -> Result<()> {
+    auth::init_admin().await?;
+    states::init_rate_limiter().await;
+    // each stops once shutdown is requested
+    let tasks = vec![
+        tokio::spawn(retention::block_periodic_purge()),
+        tokio::spawn(ratelimit::block_periodic_prune()),
+        tokio::spawn(backup::block_periodic_backup()),
+        tokio::spawn(reload::block_watch_config()),
+    ];
+    tokio::spawn(SHUTDOWN.block_on_signal());
+    root_future(port).await?;
+    let deadline = Duration::from_secs(states::server_config().shutdown_deadline_secs);
+    shutdown::stop_tasks(tasks, deadline).await;
+    indoc_info!("Server stopped.");
+    Ok(())
+}
 
+async fn root_future(port: Option<u16>) -> Result<()> {
     let app = Router::new()
         .route("/init-session", post(init_session))
         .route("/fetch-history", post(fetch_history))
         .route("/clear-history", post(clear_history))
+        .route("/restore-history", post(restore_history))
         .route("/ask-agent", post(ask_agent))
+        .route("/search-history", post(search_history))
+        .route("/export-history", post(export_history))
+        .route("/import-history", post(import_history))
+        .route("/usage", post(fetch_usage))
+        .route("/create-api-key", post(create_api_key))
+        .route("/list-api-keys", post(list_api_keys))
+        .route("/revoke-api-key", post(revoke_api_key))
         .route("/test-auth", post(test_auth))
+        .route("/admin/list-users", post(admin_list_users))
+        .route("/admin/fetch-history", post(admin_fetch_history))
+        .route("/admin/clear-history", post(admin_clear_history))
+        .route("/admin/restore-history", post(admin_restore_history))
+        .route("/admin/export-history", post(admin_export_history))
+        .route("/admin/cost", post(admin_cost))
+        .route("/admin/backup", post(admin_backup))
+        .route("/admin/purge", post(admin_purge))
+        .route("/admin/purge-runs", post(admin_purge_runs))
+        .route("/admin/pin-history", post(admin_pin_history))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(health::version))
+        .route("/metrics", get(metrics::metrics))
+        // layers run bottom-up: authenticate first, then limit by uuid
+        .route_layer(middleware::from_fn(ratelimit::limit))
+        .route_layer(middleware::from_fn(auth::enforce_role))
+        // outside the route layers, so that rejections by enforce_role and limit are counted too
+        .layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn(telemetry::trace))
         .layer(CorsLayer::very_permissive());
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
-    Ok(())
+    let config = states::server_config();
+    listen::serve(app, port, &config.listen, states::DATA_DIR.get().unwrap()).await
 }
@@ -0,0 +1,308 @@
+/// Prometheus metrics, scraped at `/metrics`.
+///
+/// Names and labels are relied on by dashboards and alerts, change them only with care.
+use std::{
+    future::Future,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::{agent::TokenUsage, shutdown::SHUTDOWN};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Upstream completions take seconds, buckets reach further than for requests.
+const LLM_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0];
+const DB_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    /// Labels `route`, `method`, `status`.
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    /// Labels `model`, `outcome` of `ok` or `error`.
+    llm_duration: HistogramVec,
+    /// Labels `model`, `kind` of `prompt` or `completion`.
+    llm_tokens: IntCounterVec,
+    /// Label `model`.
+    llm_errors: IntCounterVec,
+    /// Labels `op` as the [crate::store::Repository] method, `outcome`.
+    db_duration: HistogramVec,
+    /// Set on scrape.
+    active_generations: IntGauge,
+    /// Label `kind` of `messages` of expired conversations or `trash_messages`.
+    purged_rows: IntCounterVec,
+    /// Labels `route`, `scope` of `user` or `ip`.
+    rate_limit_rejections: IntCounterVec,
+}
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+fn histogram(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    labels: &[&str],
+    buckets: &[f64],
+) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help).buckets(buckets.to_vec());
+    let histogram = HistogramVec::new(opts, labels).unwrap();
+    registry.register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+fn outcome<T, E>(res: &Result<T, E>) -> &'static str {
+    match res {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("agent_web".into()), None).unwrap();
+        let active_generations = IntGauge::new(
+            "active_generations",
+            "Completions in flight on the upstream provider.",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(active_generations.clone()))
+            .unwrap();
+        Self {
+            http_requests: counter(
+                &registry,
+                "http_requests_total",
+                "HTTP requests by route, method and status.",
+                &["route", "method", "status"],
+            ),
+            http_duration: histogram(
+                &registry,
+                "http_request_duration_seconds",
+                "HTTP request latency by route, method and status.",
+                &["route", "method", "status"],
+                prometheus::DEFAULT_BUCKETS,
+            ),
+            llm_duration: histogram(
+                &registry,
+                "llm_request_duration_seconds",
+                "Upstream completion latency by model and outcome.",
+                &["model", "outcome"],
+                LLM_BUCKETS,
+            ),
+            llm_tokens: counter(
+                &registry,
+                "llm_tokens_total",
+                "Tokens consumed upstream by model and kind.",
+                &["model", "kind"],
+            ),
+            llm_errors: counter(
+                &registry,
+                "llm_errors_total",
+                "Failed upstream completions by model.",
+                &["model"],
+            ),
+            db_duration: histogram(
+                &registry,
+                "db_query_duration_seconds",
+                "Database latency by repository operation and outcome.",
+                &["op", "outcome"],
+                DB_BUCKETS,
+            ),
+            purged_rows: counter(
+                &registry,
+                "purged_rows_total",
+                "Messages deleted by retention, by kind.",
+                &["kind"],
+            ),
+            rate_limit_rejections: counter(
+                &registry,
+                "rate_limit_rejections_total",
+                "Requests rejected by rate limiting, by route and scope.",
+                &["route", "scope"],
+            ),
+            active_generations,
+            registry,
+        }
+    }
+
+    pub fn observe_llm<T, E>(&self, model: &str, elapsed: Duration, res: &Result<T, E>) {
+        self.llm_duration
+            .with_label_values(&[model, outcome(res)])
+            .observe(elapsed.as_secs_f64());
+        if res.is_err() {
+            self.llm_errors.with_label_values(&[model]).inc();
+        }
+    }
+
+    pub fn add_tokens(&self, model: &str, usage: &TokenUsage) {
+        for (kind, tokens) in [
+            ("prompt", usage.prompt_tokens),
+            ("completion", usage.completion_tokens),
+        ] {
+            self.llm_tokens
+                .with_label_values(&[model, kind])
+                .inc_by(tokens.max(0) as u64);
+        }
+    }
+
+    /// Run a repository call, timing it under `op`.
+    pub async fn time_db<T>(
+        &self,
+        op: &str,
+        query: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let started = Instant::now();
+        let res = query.await;
+        self.db_duration
+            .with_label_values(&[op, outcome(&res)])
+            .observe(started.elapsed().as_secs_f64());
+        res
+    }
+
+    pub fn add_purged(&self, kind: &str, rows: i64) {
+        self.purged_rows
+            .with_label_values(&[kind])
+            .inc_by(rows.max(0) as u64);
+    }
+
+    pub fn reject_rate_limit(&self, route: &str, scope: &str) {
+        self.rate_limit_rejections
+            .with_label_values(&[route, scope])
+            .inc();
+    }
+
+    /// Exposition in the Prometheus text format.
+    pub fn render(&self) -> String {
+        self.active_generations
+            .set(SHUTDOWN.active_generations() as i64);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Middleware counting and timing every request, by route template so that labels stay few.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |p| p.as_str())
+        .to_string();
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let response = next.run(req).await;
+    let status = response.status();
+    let labels = [route.as_str(), method.as_str(), status.as_str()];
+    METRICS.http_requests.with_label_values(&labels).inc();
+    METRICS
+        .http_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+pub async fn metrics() -> Response {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], METRICS.render()).into_response()
+}
+
+#[allow(unused)]
+mod test {
+    use axum::{Router, middleware, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        agent::ChatMessage,
+        config::ServerConfig,
+        listen::serve_on,
+        retention,
+        store::{Repository, metered::MeteredRepository, test::memory_repository},
+    };
+
+    #[tokio::test]
+    async fn scrape_stable_names() {
+        let app = Router::new()
+            .route("/items/{id}", get(|| async { "item" }))
+            .route("/metrics", get(metrics))
+            .layer(middleware::from_fn(track));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener, app));
+        let client = reqwest::Client::new();
+        client
+            .get(format!("http://{addr}/items/42"))
+            .send()
+            .await
+            .unwrap();
+        client
+            .get(format!("http://{addr}/nowhere"))
+            .send()
+            .await
+            .unwrap();
+
+        let usage = TokenUsage {
+            prompt_tokens: 30,
+            completion_tokens: 7,
+        };
+        METRICS.observe_llm::<_, ()>("test-model", Duration::from_millis(300), &Ok(()));
+        METRICS.observe_llm::<(), _>("test-model", Duration::from_millis(300), &Err(()));
+        METRICS.add_tokens("test-model", &usage);
+        METRICS.reject_rate_limit("/ask-agent", "user");
+
+        let repo = memory_repository().await;
+        let repo = MeteredRepository::new(Box::new(repo));
+        repo.migrate().await.unwrap();
+        let mut message = ChatMessage::create_user("metrics", "hi");
+        message.time = "2020-01-01 00:00:00".into();
+        repo.import_messages(&[message]).await.unwrap();
+        retention::purge(&repo, &ServerConfig::default(), false, "test")
+            .await
+            .unwrap();
+
+        let response = client
+            .get(format!("http://{addr}/metrics"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+        let scraped = response.text().await.unwrap();
+        for line in [
+            r#"agent_web_http_requests_total{method="GET",route="/items/{id}",status="200"} "#,
+            r#"agent_web_http_requests_total{method="GET",route="unmatched",status="404"} "#,
+            r#"agent_web_http_request_duration_seconds_bucket{method="GET",route="/items/{id}",status="200",le="+Inf"} "#,
+            r#"agent_web_llm_request_duration_seconds_count{model="test-model",outcome="ok"} "#,
+            r#"agent_web_llm_request_duration_seconds_count{model="test-model",outcome="error"} "#,
+            r#"agent_web_llm_errors_total{model="test-model"} "#,
+            r#"agent_web_llm_tokens_total{kind="prompt",model="test-model"} "#,
+            r#"agent_web_llm_tokens_total{kind="completion",model="test-model"} "#,
+            r#"agent_web_db_query_duration_seconds_count{op="purge_conversation",outcome="ok"} "#,
+            r#"agent_web_purged_rows_total{kind="messages"} "#,
+            r#"agent_web_rate_limit_rejections_total{route="/ask-agent",scope="user"} "#,
+            "agent_web_active_generations ",
+        ] {
+            assert!(scraped.contains(line), "{line} not in\n{scraped}");
+        }
+    }
+}
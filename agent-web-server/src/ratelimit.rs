@@ -0,0 +1,218 @@
+/// Token bucket rate limiting per route, keyed by user uuid and client ip.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::prelude::FromRow;
+
+use crate::{
+    auth::JwtClaim,
+    config::BucketRule,
+    indoc_info,
+    listen::ClientAddr,
+    metrics::METRICS,
+    shutdown::SHUTDOWN,
+    states::{RATE_LIMITER, server_config},
+};
+
+/// Buckets untouched for this long are refilled anyway, drop them.
+const IDLE_PRUNE_SECS: f64 = 86400.0;
+
+#[derive(FromRow, Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    /// Unix time in seconds, wall clock so that persisted buckets stay valid.
+    pub updated_at: f64,
+}
+
+impl TokenBucket {
+    pub fn full(rule: &BucketRule, now: f64) -> Self {
+        Self {
+            tokens: rule.capacity,
+            updated_at: now,
+        }
+    }
+
+    /// Take one token, or return how long until one is available.
+    pub fn take(&mut self, rule: &BucketRule, now: f64) -> Result<(), Duration> {
+        let elapsed = (now - self.updated_at).max(0.0);
+        let refill_per_sec = rule.refill_per_min / 60.0;
+        self.tokens = (self.tokens + elapsed * refill_per_sec).min(rule.capacity);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if refill_per_sec > 0.0 {
+            // a tiny rate overflows Duration
+            let wait = (1.0 - self.tokens) / refill_per_sec;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    persist: bool,
+}
+
+impl RateLimiter {
+    /// Build limiter, restoring buckets from database in persist mode.
+    pub async fn new(persist: bool) -> Self {
+        let buckets = if persist {
+            let buckets = TokenBucket::load_all().await;
+            indoc_info!("Restored {} rate limit buckets.", buckets.len());
+            buckets
+        } else {
+            HashMap::new()
+        };
+        Self {
+            buckets: Mutex::new(buckets),
+            persist,
+        }
+    }
+
+    async fn check(&self, key: String, rule: &BucketRule) -> Result<(), Duration> {
+        let now = unix_now();
+        let (res, bucket) = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| TokenBucket::full(rule, now));
+            (bucket.take(rule, now), *bucket)
+        };
+        if self.persist {
+            bucket.persist(&key).await;
+        }
+        res
+    }
+
+    async fn prune(&self) -> usize {
+        let threshold = unix_now() - IDLE_PRUNE_SECS;
+        let removed = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let before = buckets.len();
+            buckets.retain(|_, b| b.updated_at >= threshold);
+            before - buckets.len()
+        };
+        if self.persist {
+            TokenBucket::delete_idle(threshold).await;
+        }
+        removed
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Middleware applying the limits configured for the route.
+///
+/// Runs after [crate::auth::enforce_role], so the claim is available
+/// for authenticated routes.
+pub async fn limit(req: Request, next: Next) -> Response {
+    let config = server_config();
+    let limiter = RATE_LIMITER.get().unwrap();
+    let path = req.uri().path().to_string();
+    let Some(route_limit) = config.rate_limit.routes.get(&path) else {
+        return next.run(req).await;
+    };
+
+    let uuid = req.extensions().get::<JwtClaim>().map(|c| c.uuid.clone());
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<ClientAddr>>()
+        .and_then(|ci| ci.0.0)
+        .map(|addr| addr.ip());
+
+    let mut checks = Vec::new();
+    if let (Some(rule), Some(uuid)) = (&route_limit.per_user, uuid) {
+        checks.push(("user", format!("{path}|user|{uuid}"), rule));
+    }
+    if let (Some(rule), Some(ip)) = (&route_limit.per_ip, ip) {
+        checks.push(("ip", format!("{path}|ip|{ip}"), rule));
+    }
+    for (scope, key, rule) in checks {
+        if let Err(wait) = limiter.check(key, rule).await {
+            METRICS.reject_rate_limit(&path, scope);
+            let secs = wait.as_secs_f64().ceil().min(u32::MAX as f64) as u64;
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, secs.to_string())],
+                "Rate limit exceeded.".to_string(),
+            )
+                .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+pub async fn block_periodic_prune() {
+    let mut interval = tokio::time::interval(Duration::from_secs(600));
+    let limiter = RATE_LIMITER.get().unwrap();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = SHUTDOWN.requested() => break,
+        }
+        let removed = limiter.prune().await;
+        indoc_info!("Scheduled prune rate limit: {removed} idle buckets removed.");
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_drains_and_refills() {
+        let rule = BucketRule {
+            capacity: 3.0,
+            refill_per_min: 6.0,
+        };
+        let mut bucket = TokenBucket::full(&rule, 0.0);
+        for _ in 0..3 {
+            assert!(bucket.take(&rule, 0.0).is_ok());
+        }
+        let wait = bucket.take(&rule, 0.0).unwrap_err();
+        assert_eq!(wait.as_secs(), 10);
+        assert!(bucket.take(&rule, 5.0).is_err());
+        assert!(bucket.take(&rule, 10.0).is_ok());
+        // never refills beyond capacity
+        let mut bucket = TokenBucket::full(&rule, 0.0);
+        assert!(bucket.take(&rule, 1e6).is_ok());
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn bucket_without_refill() {
+        let rule = BucketRule {
+            capacity: 1.0,
+            refill_per_min: 0.0,
+        };
+        let mut bucket = TokenBucket::full(&rule, 0.0);
+        assert!(bucket.take(&rule, 0.0).is_ok());
+        assert_eq!(bucket.take(&rule, 100.0), Err(Duration::MAX));
+
+        let rule = BucketRule {
+            capacity: 1.0,
+            refill_per_min: 1e-300,
+        };
+        let mut bucket = TokenBucket::full(&rule, 0.0);
+        assert!(bucket.take(&rule, 0.0).is_ok());
+        assert_eq!(bucket.take(&rule, 100.0), Err(Duration::MAX));
+    }
+}
@@ -0,0 +1,305 @@
+/// Hot reload of config when `config.toml` changes or on SIGHUP.
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use toml::Value;
+
+use crate::{
+    config::{self, CONFIG_FILE, SECRET_KEYS, ServerConfig},
+    indoc_info, indoc_warn,
+    shutdown::SHUTDOWN,
+    states::{self, COMMAND_LINE_ARGS, DATA_DIR},
+};
+
+/// Fields read once at startup, a change keeps the running value until restart.
+pub const RESTART_KEYS: &[&str] = &[
+    "database_url",
+    "db_pool_size",
+    "encryption",
+    "rate_limit.persist",
+    "retention.interval_secs",
+    "backup.interval_hours",
+    "listen",
+    "telemetry",
+    "log",
+];
+
+/// Editors write a file in several steps, events within this delay make one reload.
+const SETTLE: Duration = Duration::from_millis(300);
+
+/// Change of one leaf key, values in TOML syntax, `None` if unset.
+#[derive(Debug, PartialEq)]
+pub struct ConfigChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "(unset)".into());
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.key,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    SECRET_KEYS.contains(&key)
+}
+
+pub fn needs_restart(key: &str) -> bool {
+    RESTART_KEYS.iter().any(|r| {
+        key.strip_prefix(r)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// Leaf values by dotted key, arrays count as leaves.
+fn flatten(prefix: &str, value: Value, out: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                let key = match prefix {
+                    "" => key,
+                    _ => format!("{prefix}.{key}"),
+                };
+                flatten(&key, value, out);
+            }
+        }
+        value => {
+            out.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+fn leaves(config: &ServerConfig) -> BTreeMap<String, Value> {
+    let mut out = BTreeMap::new();
+    // a config always serializes, it was deserialized from TOML
+    if let Ok(value) = Value::try_from(config) {
+        flatten("", value, &mut out);
+    }
+    out
+}
+
+/// Changed keys in order, secret values redacted.
+pub fn diff(old: &ServerConfig, new: &ServerConfig) -> Vec<ConfigChange> {
+    let mut old = leaves(old);
+    let mut new = leaves(new);
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).cloned().collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter_map(|key| {
+            let (old, new) = (old.remove(&key), new.remove(&key));
+            if old == new {
+                return None;
+            }
+            let show = |v: Option<Value>| match v {
+                Some(_) if is_secret(&key) => Some("<redacted>".to_string()),
+                v => v.map(|v| v.to_string()),
+            };
+            Some(ConfigChange {
+                old: show(old),
+                new: show(new),
+                key,
+            })
+        })
+        .collect()
+}
+
+/// Keep fields of [RESTART_KEYS] at their running value.
+fn keep_restart_fields(new: &mut ServerConfig, running: &ServerConfig) {
+    new.database_url = running.database_url.clone();
+    new.db_pool_size = running.db_pool_size;
+    new.encryption = running.encryption.clone();
+    new.rate_limit.persist = running.rate_limit.persist;
+    new.retention.interval_secs = running.retention.interval_secs;
+    new.backup.interval_hours = running.backup.interval_hours;
+    new.listen = running.listen.clone();
+    new.telemetry = running.telemetry.clone();
+    new.log = running.log.clone();
+}
+
+/// Config to put in effect, with changes applied and those waiting for a restart.
+pub fn plan(
+    running: &ServerConfig,
+    mut new: ServerConfig,
+) -> (ServerConfig, Vec<ConfigChange>, Vec<ConfigChange>) {
+    let (pending, applied) = diff(running, &new)
+        .into_iter()
+        .partition(|c| needs_restart(&c.key));
+    keep_restart_fields(&mut new, running);
+    (new, applied, pending)
+}
+
+/// Load config from all layers again and swap it in, the running one stays on error.
+pub fn reload() -> anyhow::Result<()> {
+    let sets = &COMMAND_LINE_ARGS.get().unwrap().set;
+    let new = config::load_config(sets)?;
+    let (new, applied, pending) = plan(&states::server_config(), new);
+    if !pending.is_empty() {
+        let lines: Vec<_> = pending.iter().map(ToString::to_string).collect();
+        indoc_warn!(
+            "
+            Config changes need a restart, running values kept:
+            {}
+            ",
+            lines.join("\n")
+        );
+    }
+    if applied.is_empty() {
+        indoc_info!("Config reloaded, nothing to apply.");
+        return Ok(());
+    }
+    states::swap_config(new);
+    let lines: Vec<_> = applied.iter().map(ToString::to_string).collect();
+    indoc_info!(
+        "
+        Config reloaded, changes:
+        {}
+        ",
+        lines.join("\n")
+    );
+    Ok(())
+}
+
+/// Whether the latest reload failed, the running config is then stale.
+/// The error is only logged, it may quote secrets of the file.
+static LAST_FAILED: AtomicBool = AtomicBool::new(false);
+
+pub fn last_failed() -> bool {
+    LAST_FAILED.load(Ordering::Relaxed)
+}
+
+fn reload_or_warn(trigger: &str) {
+    let res = reload();
+    if let Err(e) = &res {
+        indoc_warn!(
+            "
+            Config reload on {trigger} failed, running config kept, error:
+            {e:#}
+            "
+        );
+    }
+    LAST_FAILED.store(res.is_err(), Ordering::Relaxed);
+}
+
+/// Reload on change of the config file, or on SIGHUP for changes elsewhere,
+/// e.g. secret files or environment of the process.
+pub async fn block_watch_config() {
+    let data_dir = DATA_DIR.get().unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let file_tx = tx.clone();
+    // the directory is watched, editors often replace the file instead of writing it
+    let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let Ok(event) = res else {
+            return;
+        };
+        let on_config = event
+            .paths
+            .iter()
+            .any(|p| p.file_name().is_some_and(|n| n == CONFIG_FILE));
+        if on_config && !matches!(event.kind, EventKind::Access(_)) {
+            let _ = file_tx.send("file change");
+        }
+    })
+    .and_then(|mut watcher| {
+        watcher.watch(data_dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    // dropping the watcher stops it
+    let _watcher = match watcher {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            indoc_warn!(
+                "
+                Can't watch {CONFIG_FILE}, reload on SIGHUP only, error:
+                {e}
+                "
+            );
+            None
+        }
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            indoc_warn!("Can't listen to SIGHUP, config reloads on file change only.");
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            let _ = tx.send("SIGHUP");
+        }
+    });
+
+    loop {
+        let trigger = tokio::select! {
+            Some(trigger) = rx.recv() => trigger,
+            _ = SHUTDOWN.requested() => break,
+        };
+        tokio::time::sleep(SETTLE).await;
+        while rx.try_recv().is_ok() {}
+        reload_or_warn(trigger);
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diff_and_restart_keys() {
+        let running = ServerConfig::default();
+        let mut new = ServerConfig {
+            model: "gpt-next".into(),
+            api_key: "sk-new".into(),
+            db_pool_size: 99,
+            database_url: Some("postgres://u:pw@db/agent".into()),
+            ..Default::default()
+        };
+        new.retention.trash_days = 1;
+        new.encryption.enabled = true;
+        new.quota.roles.remove("user");
+
+        let changes = diff(&running, &new);
+        let lines: Vec<_> = changes.iter().map(ToString::to_string).collect();
+        assert!(lines.contains(&"api_key: <redacted> -> <redacted>".to_string()));
+        assert!(lines.contains(&"database_url: (unset) -> <redacted>".to_string()));
+        assert!(lines.contains(&"model: \"chatgpt-4o-latest\" -> \"gpt-next\"".to_string()));
+        assert!(lines.contains(&"quota.roles.user.daily_tokens: 100000 -> (unset)".to_string()));
+        assert!(
+            !lines
+                .iter()
+                .any(|l| l.contains("sk-new") || l.contains("pw@"))
+        );
+
+        let (effective, applied, pending) = plan(&running, new);
+        let keys = |c: &[ConfigChange]| c.iter().map(|c| c.key.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            keys(&pending),
+            ["database_url", "db_pool_size", "encryption.enabled"]
+        );
+        assert!(keys(&applied).contains(&"retention.trash_days".to_string()));
+        assert_eq!(effective.model, "gpt-next");
+        assert_eq!(effective.retention.trash_days, 1);
+        assert_eq!(effective.db_pool_size, running.db_pool_size);
+        assert_eq!(effective.database_url, None);
+        assert!(!effective.encryption.enabled);
+        assert!(diff(&running, &ServerConfig::default()).is_empty());
+        assert!(needs_restart("encryption.key_file"));
+        assert!(!needs_restart("encryption_extra"));
+    }
+}
@@ -0,0 +1,333 @@
+/// Retention of chat history: how long each conversation is kept, and purging the expired.
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use time::OffsetDateTime;
+
+use crate::{
+    agent::TIME_FORMAT,
+    config::ServerConfig,
+    indoc_info, indoc_warn,
+    metrics::METRICS,
+    shutdown::SHUTDOWN,
+    states::server_config,
+    store::{self, Repository},
+};
+
+/// One conversation as seen by retention.
+#[derive(FromRow, Debug, Clone)]
+pub struct ConversationActivity {
+    pub uuid: String,
+    /// Absent if the user no longer exists.
+    pub role: Option<String>,
+    pub last_active: String,
+    pub messages: i64,
+    pub pinned: bool,
+}
+
+#[derive(FromRow, Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct PurgeSummary {
+    pub conversations: i64,
+    pub messages: i64,
+    /// Expired conversations kept because they are pinned.
+    pub pinned_kept: i64,
+    /// Cleared messages deleted after the trash window.
+    pub trash_messages: i64,
+}
+
+/// Audit record of a purge.
+#[derive(FromRow, Debug, Serialize)]
+pub struct PurgeRun {
+    pub id: i64,
+    pub time: String,
+    /// `schedule`, or `admin <uuid>` if triggered by an admin.
+    pub source: String,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub summary: PurgeSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurgeCandidate {
+    pub uuid: String,
+    pub role: Option<String>,
+    pub last_active: String,
+    pub messages: i64,
+    pub retention_days: u64,
+    /// Messages at or after this time keep the conversation.
+    #[serde(skip)]
+    cutoff: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurgeReport {
+    pub dry_run: bool,
+    #[serde(flatten)]
+    pub summary: PurgeSummary,
+    /// Purged conversations, or those to be purged on dry run.
+    pub expired: Vec<PurgeCandidate>,
+}
+
+/// User override, then role override, then `chat_expire_days`.
+pub fn resolve_days(config: &ServerConfig, uuid: &str, role: Option<&str>) -> u64 {
+    let rules = &config.retention;
+    rules
+        .users
+        .get(uuid)
+        .or_else(|| role.and_then(|r| rules.roles.get(r)))
+        .copied()
+        .unwrap_or(config.chat_expire_days)
+}
+
+/// `days` before now in the format of message times, `None` if out of range.
+fn days_ago(now: OffsetDateTime, days: u64) -> Option<String> {
+    let days = i64::try_from(days).ok()?;
+    now.checked_sub(time::Duration::days(days))?
+        .format(TIME_FORMAT)
+        .ok()
+}
+
+/// Messages cleared before this time are out of the trash window.
+pub fn trash_cutoff(config: &ServerConfig, now: OffsetDateTime) -> String {
+    days_ago(now, config.retention.trash_days).unwrap_or_else(|| "0001-01-01 00:00:00".into())
+}
+
+/// Conversations expired as of now, and the number of expired ones kept by a pin.
+pub fn plan(
+    config: &ServerConfig,
+    activity: Vec<ConversationActivity>,
+    now: OffsetDateTime,
+) -> (Vec<PurgeCandidate>, i64) {
+    let mut candidates = Vec::new();
+    let mut pinned_kept = 0;
+    for conversation in activity {
+        let days = resolve_days(config, &conversation.uuid, conversation.role.as_deref());
+        // 0 days, or too many to subtract, keeps forever
+        let Some(cutoff) = days_ago(now, days).filter(|_| days > 0) else {
+            continue;
+        };
+        // same format on both sides, so text order is time order
+        if conversation.last_active >= cutoff {
+            continue;
+        }
+        if conversation.pinned {
+            pinned_kept += 1;
+            continue;
+        }
+        candidates.push(PurgeCandidate {
+            uuid: conversation.uuid,
+            role: conversation.role,
+            last_active: conversation.last_active,
+            messages: conversation.messages,
+            retention_days: days,
+            cutoff,
+        });
+    }
+    (candidates, pinned_kept)
+}
+
+/// Delete expired conversations and trash, or only report them on dry run.
+/// Real runs are recorded with source in the audit table.
+pub async fn purge(
+    repo: &dyn Repository,
+    config: &ServerConfig,
+    dry_run: bool,
+    source: &str,
+) -> anyhow::Result<PurgeReport> {
+    let now = OffsetDateTime::now_utc();
+    let activity = repo.conversation_activity().await?;
+    let (mut candidates, pinned_kept) = plan(config, activity, now);
+    let trash_before = trash_cutoff(config, now);
+    let trash_messages = if dry_run {
+        repo.count_trash(&trash_before).await?
+    } else {
+        repo.purge_trash(&trash_before).await? as i64
+    };
+    if !dry_run {
+        let mut purged = Vec::new();
+        for mut candidate in candidates {
+            // the conversation may have become active or pinned since planning
+            let removed = repo
+                .purge_conversation(&candidate.uuid, &candidate.cutoff)
+                .await?;
+            if removed > 0 {
+                candidate.messages = removed as i64;
+                purged.push(candidate);
+            }
+        }
+        candidates = purged;
+    }
+    let summary = PurgeSummary {
+        conversations: candidates.len() as i64,
+        messages: candidates.iter().map(|c| c.messages).sum(),
+        pinned_kept,
+        trash_messages,
+    };
+    if !dry_run {
+        repo.record_purge_run(source, &summary).await?;
+        METRICS.add_purged("messages", summary.messages);
+        METRICS.add_purged("trash_messages", summary.trash_messages);
+    }
+    Ok(PurgeReport {
+        dry_run,
+        summary,
+        expired: candidates,
+    })
+}
+
+/// Run the `purge` subcommand, printing the report as JSON.
+pub async fn purge_command(dry_run: bool) -> anyhow::Result<()> {
+    let report = purge(store::repo(), &server_config(), dry_run, "cli").await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+pub async fn block_periodic_purge() {
+    let interval_secs = server_config().retention.interval_secs;
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = SHUTDOWN.requested() => break,
+        }
+        // rules as reloaded by now
+        let config = server_config();
+        match purge(store::repo(), &config, false, "schedule").await {
+            Ok(report) => indoc_info!(
+                "Scheduled purge: {} conversations, {} messages removed, {} pinned kept, {} trashed messages removed.",
+                report.summary.conversations,
+                report.summary.messages,
+                report.summary.pinned_kept,
+                report.summary.trash_messages
+            ),
+            Err(e) => indoc_warn!(
+                "
+                Scheduled purge failed, error:
+                {e:#}
+                "
+            ),
+        }
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+    use crate::{agent::ChatMessage, store::test::memory_repository};
+
+    fn activity(uuid: &str, role: &str, last_active: &str, pinned: bool) -> ConversationActivity {
+        ConversationActivity {
+            uuid: uuid.into(),
+            role: Some(role.into()),
+            last_active: last_active.into(),
+            messages: 2,
+            pinned,
+        }
+    }
+
+    #[test]
+    fn retention_precedence() {
+        let mut config = ServerConfig {
+            chat_expire_days: 30,
+            ..Default::default()
+        };
+        config.retention.roles.insert("admin".into(), 0);
+        config.retention.roles.insert("user".into(), 7);
+        config.retention.users.insert("vip".into(), 365);
+        assert_eq!(resolve_days(&config, "a", Some("user")), 7);
+        assert_eq!(resolve_days(&config, "vip", Some("user")), 365);
+        assert_eq!(resolve_days(&config, "a", Some("admin")), 0);
+        assert_eq!(resolve_days(&config, "gone", None), 30);
+
+        let now = OffsetDateTime::parse(
+            "2025-06-30T00:00:00Z",
+            &time::format_description::well_known::Rfc3339,
+        )
+        .unwrap();
+        let (candidates, pinned_kept) = plan(
+            &config,
+            vec![
+                activity("a", "user", "2025-06-01 00:00:00", false),
+                activity("b", "user", "2025-06-29 00:00:00", false),
+                activity("vip", "user", "2025-06-01 00:00:00", false),
+                activity("root", "admin", "2000-01-01 00:00:00", false),
+                activity("c", "user", "2025-06-01 00:00:00", true),
+            ],
+            now,
+        );
+        let uuids: Vec<_> = candidates.iter().map(|c| c.uuid.as_str()).collect();
+        assert_eq!(uuids, ["a"]);
+        assert_eq!(candidates[0].cutoff, "2025-06-23 00:00:00");
+        assert_eq!(pinned_kept, 1);
+    }
+
+    #[tokio::test]
+    async fn purge_with_pins_and_audit() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        let mut old = Vec::new();
+        for uuid in ["old", "pinned"] {
+            let mut message = ChatMessage::create_user(uuid, "hi");
+            message.time = "2020-01-01 00:00:00".into();
+            old.push(message);
+        }
+        repo.import_messages(&old).await.unwrap();
+        repo.persist_message(&ChatMessage::create_user("fresh", "hi"))
+            .await
+            .unwrap();
+        repo.set_pinned("pinned", true).await.unwrap();
+        let config = ServerConfig::default();
+
+        let report = purge(&repo, &config, true, "test").await.unwrap();
+        assert_eq!(report.expired[0].uuid, "old");
+        let expected = PurgeSummary {
+            conversations: 1,
+            messages: 1,
+            pinned_kept: 1,
+            trash_messages: 0,
+        };
+        assert_eq!(report.summary, expected);
+        assert_eq!(repo.load_history("old").await.unwrap().len(), 1);
+        assert!(repo.list_purge_runs(10).await.unwrap().is_empty());
+
+        let report = purge(&repo, &config, false, "test").await.unwrap();
+        assert_eq!(report.summary, expected);
+        assert!(repo.load_history("old").await.unwrap().is_empty());
+        assert_eq!(repo.load_history("pinned").await.unwrap().len(), 1);
+        assert_eq!(repo.load_history("fresh").await.unwrap().len(), 1);
+        let runs = repo.list_purge_runs(10).await.unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].source, "test");
+        assert_eq!(runs[0].summary, expected);
+
+        // unpinned, it expires like any other
+        repo.set_pinned("pinned", false).await.unwrap();
+        let report = purge(&repo, &config, false, "test").await.unwrap();
+        assert_eq!(report.summary.conversations, 1);
+        assert_eq!(report.summary.pinned_kept, 0);
+        assert_eq!(
+            repo.list_purge_runs(1).await.unwrap()[0].summary,
+            report.summary
+        );
+
+        // cleared history stays restorable within the trash window
+        repo.clear_history("fresh").await.unwrap();
+        let report = purge(&repo, &config, false, "test").await.unwrap();
+        assert_eq!(report.summary.trash_messages, 0);
+        assert_eq!(
+            repo.restore_history("fresh", &trash_cutoff(&config, OffsetDateTime::now_utc()))
+                .await
+                .unwrap(),
+            1
+        );
+        repo.clear_history("fresh").await.unwrap();
+        let later = OffsetDateTime::now_utc() + time::Duration::days(8);
+        assert_eq!(
+            repo.count_trash(&trash_cutoff(&config, later))
+                .await
+                .unwrap(),
+            1
+        );
+    }
+}
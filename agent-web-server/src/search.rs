@@ -0,0 +1,159 @@
+/// Full-text search over the caller's own chat history.
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+
+pub const DEFAULT_LIMIT: u32 = 20;
+pub const MAX_LIMIT: u32 = 100;
+
+/// Markers around matched terms in [SearchHit::snippet].
+pub const MARK_START: &str = "<mark>";
+pub const MARK_END: &str = "</mark>";
+/// Private use characters the databases mark matches with,
+/// turned into [MARK_START] and [MARK_END] once the snippet is escaped.
+pub const DB_MARK_START: &str = "\u{E000}";
+pub const DB_MARK_END: &str = "\u{E001}";
+
+#[derive(FromRow, Debug, Serialize)]
+pub struct SearchHit {
+    pub id: i64,
+    pub role: String,
+    /// Excerpt of the message, HTML escaped, matches wrapped in `<mark>`.
+    pub snippet: String,
+    pub time: String,
+}
+
+impl SearchHit {
+    /// Escape a snippet marked with [DB_MARK_START] and [DB_MARK_END].
+    pub fn escape_db_snippet(mut self) -> Self {
+        self.snippet = escape_html(&self.snippet)
+            .replace(DB_MARK_START, MARK_START)
+            .replace(DB_MARK_END, MARK_END);
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub hits: Vec<SearchHit>,
+    /// Offset of the next page, absent on the last page.
+    pub next_offset: Option<u32>,
+}
+
+/// Best matches first, messages of other users are never considered.
+pub async fn search(uuid: &str, query: &str, offset: u32, limit: Option<u32>) -> SearchPage {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    // one extra row tells whether there is a next page
+    let mut hits = SearchHit::find(uuid, query, offset, limit + 1).await;
+    let next_offset = if hits.len() > limit as usize {
+        hits.truncate(limit as usize);
+        Some(offset + limit)
+    } else {
+        None
+    };
+    SearchPage { hits, next_offset }
+}
+
+/// Words of an excerpt, like the snippets of the database backends.
+const SNIPPET_WORDS: usize = 16;
+
+/// Whether content contains every word, ASCII case-insensitive,
+/// for backends that can't search in the database.
+pub fn matches_all(content: &str, words: &[String]) -> bool {
+    let content = content.to_ascii_lowercase();
+    words.iter().all(|w| content.contains(w.as_str()))
+}
+
+/// Escaped excerpt of content around the first match, occurrences of words marked.
+/// Words must be ASCII lowercase, as from [query_words].
+pub fn highlight(content: &str, words: &[String]) -> String {
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+    let first = tokens
+        .iter()
+        .position(|t| {
+            words
+                .iter()
+                .any(|w| t.to_ascii_lowercase().contains(w.as_str()))
+        })
+        .unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_WORDS / 4);
+    let end = (start + SNIPPET_WORDS).min(tokens.len());
+    let mut snippet = tokens[start..end]
+        .iter()
+        .map(|t| mark(t, words))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < tokens.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Escape text for HTML content and attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn query_words(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .map(|w| w.to_ascii_lowercase())
+        .collect()
+}
+
+fn mark(token: &str, words: &[String]) -> String {
+    // ASCII lowercasing keeps byte offsets valid in token
+    let lower = token.to_ascii_lowercase();
+    let mut marked = String::new();
+    let mut pos = 0;
+    while let Some((at, len)) = words
+        .iter()
+        .filter_map(|w| lower[pos..].find(w.as_str()).map(|i| (pos + i, w.len())))
+        .min()
+    {
+        marked.push_str(&escape_html(&token[pos..at]));
+        marked.push_str(MARK_START);
+        marked.push_str(&escape_html(&token[at..at + len]));
+        marked.push_str(MARK_END);
+        pos = at + len;
+    }
+    marked.push_str(&escape_html(&token[pos..]));
+    marked
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn highlight_in_memory() {
+        let words = query_words("Regex ü");
+        assert!(matches_all("Use a REGEX, ü", &words));
+        assert!(!matches_all("Use a REGEX", &words));
+        assert_eq!(
+            highlight("Use a REGEX, über", &words),
+            "Use a <mark>REGEX</mark>, <mark>ü</mark>ber"
+        );
+        let long = (0..40).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        let snippet = highlight(&long, &query_words("20"));
+        assert!(snippet.starts_with("…16 17 18 19 <mark>20</mark> 21"));
+        assert!(snippet.ends_with("31…"));
+        assert_eq!(
+            highlight("<script>alert('x')</script>", &query_words("alert")),
+            "&lt;script&gt;<mark>alert</mark>(&#39;x&#39;)&lt;/script&gt;"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+/// Graceful shutdown on SIGTERM or Ctrl-C: stop accepting, let generations finish within
+/// `shutdown_deadline_secs`, interrupt the rest, then stop background tasks.
+use std::{future::Future, sync::LazyLock, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{indoc_info, indoc_warn};
+
+/// Interrupted generations get this long to persist and return.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
+pub static SHUTDOWN: LazyLock<Shutdown> = LazyLock::new(Shutdown::new);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// No new connection, in-flight requests finish.
+    Draining,
+    /// Deadline passed, generations persist what they have and return.
+    Interrupting,
+}
+
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+    generations: watch::Sender<usize>,
+}
+
+/// Counts as in-flight generation while alive.
+pub struct Generation<'a>(&'a Shutdown);
+
+impl Drop for Generation<'_> {
+    fn drop(&mut self) {
+        self.0.generations.send_modify(|n| *n -= 1);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            phase: watch::Sender::new(Phase::Running),
+            generations: watch::Sender::new(0),
+        }
+    }
+
+    /// Move on to `phase`, never back.
+    pub fn enter(&self, phase: Phase) {
+        self.phase.send_if_modified(|current| {
+            let later = phase > *current;
+            if later {
+                *current = phase;
+            }
+            later
+        });
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut rx = self.phase.subscribe();
+        // the sender lives as long as self
+        let _ = rx.wait_for(|current| *current >= phase).await;
+    }
+
+    /// Resolves once shutdown is requested.
+    pub async fn requested(&self) {
+        self.reached(Phase::Draining).await
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.phase.borrow() >= Phase::Draining
+    }
+
+    /// Resolves once the drain deadline has passed.
+    pub async fn interrupted(&self) {
+        self.reached(Phase::Interrupting).await
+    }
+
+    pub fn generation(&self) -> Generation<'_> {
+        self.generations.send_modify(|n| *n += 1);
+        Generation(self)
+    }
+
+    pub fn active_generations(&self) -> usize {
+        *self.generations.borrow()
+    }
+
+    async fn generations_done(&self) {
+        let mut rx = self.generations.subscribe();
+        let _ = rx.wait_for(|n| *n == 0).await;
+    }
+
+    /// Drain on the first SIGTERM or Ctrl-C, interrupt generations right away on the second.
+    pub async fn block_on_signal(&self) {
+        #[cfg(unix)]
+        let mut terminate =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(terminate) => Some(terminate),
+                Err(e) => {
+                    indoc_warn!("Can't listen to SIGTERM, shutdown on Ctrl-C only, error: {e}");
+                    None
+                }
+            };
+        for phase in [Phase::Draining, Phase::Interrupting] {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                Some(_) = async { terminate.as_mut()?.recv().await } => {}
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+            self.enter(phase);
+        }
+    }
+
+    /// Serve until `serving` ends, or until drained after shutdown is requested.
+    /// Generations still running at the deadline are interrupted, other connections dropped.
+    /// `deadline` is read once shutdown is requested, so that it can be reloaded until then.
+    pub async fn drain<F>(
+        &self,
+        serving: F,
+        deadline: impl FnOnce() -> Duration,
+    ) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        tokio::pin!(serving);
+        tokio::select! {
+            // before serving, which ends at once on request if idle
+            biased;
+            _ = self.requested() => {}
+            res = &mut serving => return res,
+        }
+        let deadline = deadline();
+        indoc_info!(
+            "Shutting down, draining {} generations within {deadline:?}...",
+            self.active_generations()
+        );
+        tokio::select! {
+            res = &mut serving => return res,
+            _ = tokio::time::sleep(deadline) => {}
+            _ = self.interrupted() => {}
+        }
+        indoc_warn!(
+            "Shutdown deadline passed, interrupting {} generations.",
+            self.active_generations()
+        );
+        self.enter(Phase::Interrupting);
+        if tokio::time::timeout(INTERRUPT_GRACE, self.generations_done())
+            .await
+            .is_err()
+        {
+            indoc_warn!(
+                "{} generations did not return in time.",
+                self.active_generations()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Wait for background tasks to finish their run in progress, they stop once
+/// shutdown is requested. Those still busy after `deadline` are aborted.
+pub async fn stop_tasks(tasks: Vec<JoinHandle<()>>, deadline: Duration) {
+    let count = tasks.len();
+    let aborts: Vec<_> = tasks.iter().map(JoinHandle::abort_handle).collect();
+    let joined = tokio::time::timeout(deadline, async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    })
+    .await;
+    match joined {
+        Ok(()) => indoc_info!("{count} background tasks stopped."),
+        Err(_) => {
+            aborts.iter().for_each(|task| task.abort());
+            indoc_warn!("Background tasks still busy after {deadline:?}, aborted.");
+        }
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_then_interrupt() {
+        let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+        let persisted = Arc::new(AtomicBool::new(false));
+        let generation = shutdown.generation();
+        let handler_persisted = persisted.clone();
+        // a generation never answering, as a hung upstream would
+        tokio::spawn(async move {
+            let _generation = generation;
+            shutdown.interrupted().await;
+            handler_persisted.store(true, Ordering::SeqCst);
+        });
+        let serving = std::future::pending();
+
+        shutdown.enter(Phase::Draining);
+        let started = tokio::time::Instant::now();
+        shutdown
+            .drain(serving, || Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(persisted.load(Ordering::SeqCst));
+        assert_eq!(shutdown.active_generations(), 0);
+        // never back to an earlier phase
+        shutdown.enter(Phase::Draining);
+        assert_eq!(*shutdown.phase.borrow(), Phase::Interrupting);
+
+        // served to the end before the deadline, nothing interrupted
+        let shutdown = Shutdown::new();
+        shutdown.enter(Phase::Draining);
+        let serving = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok(())
+        };
+        shutdown
+            .drain(serving, || Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(*shutdown.phase.borrow(), Phase::Draining);
+    }
+}
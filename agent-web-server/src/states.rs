@@ -1,22 +1,31 @@
-use std::{env, fs::create_dir_all, path::PathBuf, sync::OnceLock};
+use std::{
+    env,
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use async_openai::{Client, config::OpenAIConfig};
 use jwt_simple::prelude::HS256Key;
-use sqlx::SqlitePool;
 
 use crate::{
     CommandLineArgs, auth,
     config::{self, ServerConfig},
-    indoc_info, store,
+    indoc_info,
+    ratelimit::RateLimiter,
+    store::{self, Repository},
 };
 
 pub static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
-pub static SERVER_CONFIG: OnceLock<ServerConfig> = OnceLock::new();
-pub static AGENT_CLIENT: OnceLock<Client<OpenAIConfig>> = OnceLock::new();
-pub static DB_POOL: OnceLock<SqlitePool> = OnceLock::new();
+/// Swapped on reload, see [crate::reload].
+pub static SERVER_CONFIG: OnceLock<ArcSwap<ServerConfig>> = OnceLock::new();
+pub static AGENT_CLIENT: OnceLock<ArcSwap<Client<OpenAIConfig>>> = OnceLock::new();
+pub static REPOSITORY: OnceLock<Box<dyn Repository>> = OnceLock::new();
 pub static JWT_KEY: OnceLock<HS256Key> = OnceLock::new();
 pub static COMMAND_LINE_ARGS: OnceLock<CommandLineArgs> = OnceLock::new();
+pub static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
 
 // Set OnceLock value, panic in place with identifier.
 // Logically it should never fail.
@@ -28,44 +37,93 @@ macro_rules! init_once {
     };
 }
 
-/// Set all global variables.
-pub async fn init_states(cli: CommandLineArgs) -> anyhow::Result<()> {
-    init_once!(COMMAND_LINE_ARGS, cli);
-
-    // init directory
-    let exec_path = env::current_exe().with_context(|| "exec path")?;
-    let exec_path = exec_path
-        .canonicalize()
-        .with_context(|| "convert exec path to absolute path")?;
-    let exec_dir = exec_path.parent().with_context(|| "exec has no parent")?;
-    let data_dir = exec_dir.join("server_data");
+/// Set data directory, `server_data` next to the executable if not given.
+pub fn init_data_dir(data_dir: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let data_dir = match data_dir {
+        Some(data_dir) => data_dir.to_path_buf(),
+        None => {
+            let exec_path = env::current_exe().with_context(|| "exec path")?;
+            let exec_path = exec_path
+                .canonicalize()
+                .with_context(|| "convert exec path to absolute path")?;
+            let exec_dir = exec_path.parent().with_context(|| "exec has no parent")?;
+            exec_dir.join("server_data")
+        }
+    };
     create_dir_all(&data_dir).with_context(|| "create data directory")?;
 
-    init_once!(DATA_DIR, data_dir);
+    init_once!(DATA_DIR, data_dir.clone());
+    Ok(data_dir)
+}
+
+/// Set all global variables.
+/// Data directory must be set, see [init_data_dir].
+pub async fn init_states(cli: CommandLineArgs) -> anyhow::Result<()> {
+    let data_dir = DATA_DIR.get().unwrap().clone();
 
     // init config
-    let (server_config, has_config) = config::init_config()?;
+    let (server_config, has_config) = config::init_config(&cli.set)?;
     if !has_config {
-        indoc_info!("Config template generated, fill and restart.");
-        std::process::exit(1);
+        indoc_info!(
+            "
+            No {} in {}, running on defaults and overrides, `init-config` writes a template.
+            ",
+            config::CONFIG_FILE,
+            data_dir.display()
+        );
     }
+    init_once!(COMMAND_LINE_ARGS, cli);
 
     // init client
-    let openai_config = OpenAIConfig::new()
-        .with_api_base(&server_config.api_base)
-        .with_api_key(&server_config.api_key);
-    let agent_client = Client::with_config(openai_config);
+    let agent_client = new_agent_client(&server_config);
 
     // init db pool
-    let pool = store::init_sqlite_pool(server_config.db_pool_size).await?;
+    let repository = store::init_repository(&server_config, &data_dir).await?;
 
     // init jwt key
     let jwt_key_bytes = auth::init_jwt_key()?;
     let jwt_key = HS256Key::from_bytes(&jwt_key_bytes);
 
+    let agent_client = ArcSwap::from_pointee(agent_client);
+    let server_config = ArcSwap::from_pointee(server_config);
     init_once!(AGENT_CLIENT, agent_client);
     init_once!(SERVER_CONFIG, server_config);
-    init_once!(DB_POOL, pool);
+    init_once!(REPOSITORY, repository);
     init_once!(JWT_KEY, jwt_key);
     Ok(())
 }
+
+fn new_agent_client(config: &ServerConfig) -> Client<OpenAIConfig> {
+    let openai_config = OpenAIConfig::new()
+        .with_api_base(&config.api_base)
+        .with_api_key(&config.api_key);
+    Client::with_config(openai_config)
+}
+
+/// Config in effect, a request keeps the one it started with across a reload.
+pub fn server_config() -> Arc<ServerConfig> {
+    SERVER_CONFIG.get().unwrap().load_full()
+}
+
+pub fn agent_client() -> Arc<Client<OpenAIConfig>> {
+    AGENT_CLIENT.get().unwrap().load_full()
+}
+
+/// Put a reloaded config in effect, with a new client if the API changed.
+pub fn swap_config(config: ServerConfig) {
+    let current = server_config();
+    if (&current.api_base, &current.api_key) != (&config.api_base, &config.api_key) {
+        AGENT_CLIENT
+            .get()
+            .unwrap()
+            .store(Arc::new(new_agent_client(&config)));
+    }
+    SERVER_CONFIG.get().unwrap().store(Arc::new(config));
+}
+
+/// Set rate limiter, after tables are ready.
+pub async fn init_rate_limiter() {
+    let config = server_config();
+    let limiter = RateLimiter::new(config.rate_limit.persist).await;
+    init_once!(RATE_LIMITER, limiter);
+}
@@ -0,0 +1,381 @@
+/// Decorator encrypting message content on write and decrypting it on read,
+/// in front of any backend.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use super::{MigrationInfo, Repository, StoredContent, repo};
+use crate::{
+    agent::{ChatMessage, HistoryCursor, TokenUsage},
+    auth::{ApiKey, Role, User},
+    cost::{CostBucket, CostGroup},
+    crypto::Keyring,
+    indoc_info,
+    ratelimit::TokenBucket,
+    retention::{ConversationActivity, PurgeRun, PurgeSummary},
+    search::{self, SearchHit},
+    states::{DATA_DIR, server_config},
+    usage::{DailyUsage, Period},
+};
+
+#[derive(Debug)]
+pub struct EncryptedRepository {
+    inner: Box<dyn Repository>,
+    keyring: Keyring,
+}
+
+impl EncryptedRepository {
+    pub fn new(inner: Box<dyn Repository>, keyring: Keyring) -> Self {
+        Self { inner, keyring }
+    }
+
+    fn decrypt(&self, mut message: ChatMessage) -> anyhow::Result<ChatMessage> {
+        message.content = self
+            .keyring
+            .decrypt(&message.content, &message.uuid)
+            .with_context(|| format!("message {}", message.id))?;
+        Ok(message)
+    }
+
+    fn decrypt_all(&self, messages: Vec<ChatMessage>) -> anyhow::Result<Vec<ChatMessage>> {
+        messages.into_iter().map(|m| self.decrypt(m)).collect()
+    }
+
+    fn encrypt(&self, message: &ChatMessage) -> anyhow::Result<ChatMessage> {
+        Ok(ChatMessage {
+            content: self.keyring.encrypt(&message.content, &message.uuid)?,
+            ..message.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl Repository for EncryptedRepository {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.inner.migrate().await
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        self.inner.ping().await
+    }
+
+    async fn migration_status(&self) -> anyhow::Result<Vec<MigrationInfo>> {
+        self.inner.migration_status().await
+    }
+
+    async fn migrate_down(&self, target: Option<i64>) -> anyhow::Result<i64> {
+        self.inner.migrate_down(target).await
+    }
+
+    async fn snapshot(&self, dest: &Path) -> anyhow::Result<()> {
+        self.inner.snapshot(dest).await
+    }
+
+    async fn load_history(&self, uuid: &str) -> anyhow::Result<Vec<ChatMessage>> {
+        self.decrypt_all(self.inner.load_history(uuid).await?)
+    }
+
+    async fn load_history_page(
+        &self,
+        uuid: &str,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        self.decrypt_all(self.inner.load_history_page(uuid, cursor, limit).await?)
+    }
+
+    async fn persist_message(&self, message: &ChatMessage) -> anyhow::Result<(i64, String)> {
+        self.inner.persist_message(&self.encrypt(message)?).await
+    }
+
+    async fn import_messages(&self, messages: &[ChatMessage]) -> anyhow::Result<()> {
+        let encrypted = messages
+            .iter()
+            .map(|m| self.encrypt(m))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.inner.import_messages(&encrypted).await
+    }
+
+    async fn list_conversations(&self) -> anyhow::Result<Vec<String>> {
+        self.inner.list_conversations().await
+    }
+
+    async fn clear_history(&self, uuid: &str) -> anyhow::Result<u64> {
+        self.inner.clear_history(uuid).await
+    }
+
+    async fn restore_history(&self, uuid: &str, since: &str) -> anyhow::Result<u64> {
+        self.inner.restore_history(uuid, since).await
+    }
+
+    async fn count_trash(&self, before: &str) -> anyhow::Result<i64> {
+        self.inner.count_trash(before).await
+    }
+
+    async fn purge_trash(&self, before: &str) -> anyhow::Result<u64> {
+        self.inner.purge_trash(before).await
+    }
+
+    /// The database only holds ciphertext, so the decrypted history of uuid is
+    /// scanned in memory, newest matches first.
+    async fn search_history(
+        &self,
+        uuid: &str,
+        query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        let words = search::query_words(query);
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .load_history(uuid)
+            .await?
+            .into_iter()
+            .rev()
+            .filter(|m| search::matches_all(&m.content, &words))
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|m| SearchHit {
+                id: m.id,
+                snippet: search::highlight(&m.content, &words),
+                role: m.role,
+                time: m.time,
+            })
+            .collect())
+    }
+
+    async fn conversation_activity(&self) -> anyhow::Result<Vec<ConversationActivity>> {
+        self.inner.conversation_activity().await
+    }
+
+    async fn purge_conversation(&self, uuid: &str, cutoff: &str) -> anyhow::Result<u64> {
+        self.inner.purge_conversation(uuid, cutoff).await
+    }
+
+    async fn set_pinned(&self, uuid: &str, pinned: bool) -> anyhow::Result<()> {
+        self.inner.set_pinned(uuid, pinned).await
+    }
+
+    async fn record_purge_run(&self, source: &str, summary: &PurgeSummary) -> anyhow::Result<()> {
+        self.inner.record_purge_run(source, summary).await
+    }
+
+    async fn list_purge_runs(&self, limit: u32) -> anyhow::Result<Vec<PurgeRun>> {
+        self.inner.list_purge_runs(limit).await
+    }
+
+    async fn scan_contents(&self, after_id: i64, limit: u32) -> anyhow::Result<Vec<StoredContent>> {
+        self.inner.scan_contents(after_id, limit).await
+    }
+
+    async fn update_contents(&self, contents: &[StoredContent]) -> anyhow::Result<()> {
+        self.inner.update_contents(contents).await
+    }
+
+    async fn any_content_with_prefix(&self, prefix: &str) -> anyhow::Result<bool> {
+        self.inner.any_content_with_prefix(prefix).await
+    }
+
+    async fn create_user(&self, uuid: &str, role: Role) -> anyhow::Result<()> {
+        self.inner.create_user(uuid, role).await
+    }
+
+    async fn find_user(&self, uuid: &str) -> anyhow::Result<Option<User>> {
+        self.inner.find_user(uuid).await
+    }
+
+    async fn list_users(&self) -> anyhow::Result<Vec<User>> {
+        self.inner.list_users().await
+    }
+
+    async fn count_users_by_role(&self, role: Role) -> anyhow::Result<i64> {
+        self.inner.count_users_by_role(role).await
+    }
+
+    async fn set_user_disabled(&self, uuid: &str, disabled: bool) -> anyhow::Result<bool> {
+        self.inner.set_user_disabled(uuid, disabled).await
+    }
+
+    async fn create_api_key(
+        &self,
+        uuid: &str,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+    ) -> anyhow::Result<i64> {
+        self.inner
+            .create_api_key(uuid, name, key_hash, prefix)
+            .await
+    }
+
+    async fn list_api_keys(&self, uuid: &str) -> anyhow::Result<Vec<ApiKey>> {
+        self.inner.list_api_keys(uuid).await
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> anyhow::Result<Option<ApiKey>> {
+        self.inner.find_api_key(key_hash).await
+    }
+
+    async fn touch_api_key(&self, id: i64, ip: Option<String>) -> anyhow::Result<()> {
+        self.inner.touch_api_key(id, ip).await
+    }
+
+    async fn revoke_api_key(&self, uuid: &str, id: i64) -> anyhow::Result<bool> {
+        self.inner.revoke_api_key(uuid, id).await
+    }
+
+    async fn load_buckets(&self) -> anyhow::Result<HashMap<String, TokenBucket>> {
+        self.inner.load_buckets().await
+    }
+
+    async fn persist_bucket(&self, key: &str, bucket: &TokenBucket) -> anyhow::Result<()> {
+        self.inner.persist_bucket(key, bucket).await
+    }
+
+    async fn delete_idle_buckets(&self, threshold: f64) -> anyhow::Result<u64> {
+        self.inner.delete_idle_buckets(threshold).await
+    }
+
+    async fn record_usage(
+        &self,
+        uuid: &str,
+        model: &str,
+        usage: &TokenUsage,
+        cost: f64,
+    ) -> anyhow::Result<()> {
+        self.inner.record_usage(uuid, model, usage, cost).await
+    }
+
+    async fn sum_usage_since(&self, uuid: &str, period: Period) -> anyhow::Result<TokenUsage> {
+        self.inner.sum_usage_since(uuid, period).await
+    }
+
+    async fn daily_usage(&self, uuid: &str, days: u32) -> anyhow::Result<Vec<DailyUsage>> {
+        self.inner.daily_usage(uuid, days).await
+    }
+
+    async fn aggregate_cost(&self, group: CostGroup, days: u32) -> anyhow::Result<Vec<CostBucket>> {
+        self.inner.aggregate_cost(group, days).await
+    }
+
+    async fn spent_today(&self, uuid: Option<&str>) -> anyhow::Result<f64> {
+        self.inner.spent_today(uuid).await
+    }
+}
+
+/// Re-encrypt every message not sealed by the current master key, plaintext included,
+/// batch by batch in id order. Returns the number of messages rewritten.
+pub async fn rotate(
+    repo: &dyn Repository,
+    keyring: &Keyring,
+    batch_size: u32,
+) -> anyhow::Result<u64> {
+    let mut after_id = 0;
+    let mut rewritten = 0;
+    loop {
+        let batch = repo.scan_contents(after_id, batch_size).await?;
+        let Some(last) = batch.last() else {
+            return Ok(rewritten);
+        };
+        after_id = last.id;
+        let stale = batch
+            .into_iter()
+            .filter(|c| !keyring.is_current(&c.message))
+            .map(|c| {
+                let plaintext = keyring.decrypt(&c.message, &c.uuid)?;
+                Ok(StoredContent {
+                    message: keyring.encrypt(&plaintext, &c.uuid)?,
+                    ..c
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        repo.update_contents(&stale).await?;
+        rewritten += stale.len() as u64;
+        indoc_info!("Key rotation: {rewritten} messages re-encrypted, up to id {after_id}.");
+    }
+}
+
+/// Run the `rotate-key` subcommand, with retired keys listed in `previous_key_files`.
+pub async fn rotate_key_command(batch_size: u32) -> anyhow::Result<()> {
+    let config = server_config();
+    if !config.encryption.enabled {
+        anyhow::bail!("encryption is disabled, set encryption.enabled first");
+    }
+    let keyring = Keyring::load(&config.encryption, DATA_DIR.get().unwrap(), repo()).await?;
+    let rewritten = rotate(repo(), &keyring, batch_size.max(1)).await?;
+    println!("{rewritten} messages re-encrypted");
+    Ok(())
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::MasterKey,
+        store::test::{exercise_repository, memory_repository},
+    };
+
+    fn keyring(byte: u8, previous: &[u8]) -> Keyring {
+        let key = |b: u8| MasterKey::from_bytes(&[b; 32]).unwrap();
+        Keyring::new(key(byte), previous.iter().map(|&b| key(b)).collect())
+    }
+
+    #[tokio::test]
+    async fn encrypted_repository() {
+        let repo = EncryptedRepository::new(Box::new(memory_repository().await), keyring(1, &[]));
+        exercise_repository(&repo).await;
+    }
+
+    #[tokio::test]
+    async fn ciphertext_at_rest_and_rotation() {
+        let backend = memory_repository().await;
+        backend.migrate().await.unwrap();
+        // written before encryption was enabled
+        backend
+            .persist_message(&ChatMessage::create_user("u", "legacy plaintext"))
+            .await
+            .unwrap();
+
+        let old = EncryptedRepository::new(Box::new(backend), keyring(1, &[]));
+        for i in 0..5 {
+            let message = ChatMessage::create_user("u", &format!("secret {i}"));
+            old.persist_message(&message).await.unwrap();
+        }
+        let stored = old.scan_contents(0, 100).await.unwrap();
+        assert_eq!(stored[0].message, "legacy plaintext");
+        assert!(stored[1..].iter().all(|c| !c.message.contains("secret")));
+        let history = old.load_history("u").await.unwrap();
+        assert_eq!(history[0].content, "legacy plaintext");
+        assert_eq!(history[5].content, "secret 4");
+        let hits = old.search_history("u", "SECRET 4", 0, 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].snippet, "<mark>secret</mark> <mark>4</mark>");
+
+        let EncryptedRepository { inner, .. } = old;
+        let new = EncryptedRepository::new(inner, keyring(2, &[1]));
+        assert_eq!(rotate(&new, &new.keyring, 2).await.unwrap(), 6);
+        assert_eq!(rotate(&new, &new.keyring, 2).await.unwrap(), 0);
+        let stored = new.scan_contents(0, 100).await.unwrap();
+        assert!(stored.iter().all(|c| new.keyring.is_current(&c.message)));
+
+        // the old key can be retired once rotated
+        let EncryptedRepository { inner, .. } = new;
+        let retired = EncryptedRepository::new(inner, keyring(2, &[]));
+        let history = retired.load_history("u").await.unwrap();
+        assert_eq!(history[0].content, "legacy plaintext");
+        assert_eq!(history[3].content, "secret 2");
+
+        // a message no key opens fails the read instead of passing for no history
+        let stray = keyring(3, &[]).encrypt("stray", "u").unwrap();
+        let EncryptedRepository { inner, .. } = retired;
+        let mut message = ChatMessage::create_user("u", "");
+        message.content = stray;
+        inner.persist_message(&message).await.unwrap();
+        let retired = EncryptedRepository::new(inner, keyring(2, &[]));
+        let err = retired.load_history("u").await.unwrap_err();
+        assert!(format!("{err:#}").contains("unknown master key"), "{err:#}");
+    }
+}
@@ -0,0 +1,263 @@
+/// Decorator timing every call into [METRICS] and tracing it as a `db` span,
+/// in front of any backend.
+use std::{collections::HashMap, future::Future, path::Path};
+
+use async_trait::async_trait;
+use tracing::Instrument;
+
+use super::{MigrationInfo, Repository, StoredContent};
+use crate::{
+    agent::{ChatMessage, HistoryCursor, TokenUsage},
+    auth::{ApiKey, Role, User},
+    cost::{CostBucket, CostGroup},
+    metrics::METRICS,
+    ratelimit::TokenBucket,
+    retention::{ConversationActivity, PurgeRun, PurgeSummary},
+    search::SearchHit,
+    telemetry,
+    usage::{DailyUsage, Period},
+};
+
+#[derive(Debug)]
+pub struct MeteredRepository {
+    inner: Box<dyn Repository>,
+}
+
+impl MeteredRepository {
+    pub fn new(inner: Box<dyn Repository>) -> Self {
+        Self { inner }
+    }
+}
+
+async fn observe<T>(op: &str, query: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    let span = telemetry::db_span(op);
+    let res = METRICS.time_db(op, query).instrument(span.clone()).await;
+    telemetry::record_outcome(&span, &res);
+    res
+}
+
+#[async_trait]
+impl Repository for MeteredRepository {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        observe("migrate", self.inner.migrate()).await
+    }
+
+    async fn migration_status(&self) -> anyhow::Result<Vec<MigrationInfo>> {
+        observe("migration_status", self.inner.migration_status()).await
+    }
+
+    async fn migrate_down(&self, target: Option<i64>) -> anyhow::Result<i64> {
+        observe("migrate_down", self.inner.migrate_down(target)).await
+    }
+
+    async fn snapshot(&self, dest: &Path) -> anyhow::Result<()> {
+        observe("snapshot", self.inner.snapshot(dest)).await
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        observe("ping", self.inner.ping()).await
+    }
+
+    async fn load_history(&self, uuid: &str) -> anyhow::Result<Vec<ChatMessage>> {
+        observe("load_history", self.inner.load_history(uuid)).await
+    }
+
+    async fn load_history_page(
+        &self,
+        uuid: &str,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        observe(
+            "load_history_page",
+            self.inner.load_history_page(uuid, cursor, limit),
+        )
+        .await
+    }
+
+    async fn persist_message(&self, message: &ChatMessage) -> anyhow::Result<(i64, String)> {
+        observe("persist_message", self.inner.persist_message(message)).await
+    }
+
+    async fn import_messages(&self, messages: &[ChatMessage]) -> anyhow::Result<()> {
+        observe("import_messages", self.inner.import_messages(messages)).await
+    }
+
+    async fn list_conversations(&self) -> anyhow::Result<Vec<String>> {
+        observe("list_conversations", self.inner.list_conversations()).await
+    }
+
+    async fn clear_history(&self, uuid: &str) -> anyhow::Result<u64> {
+        observe("clear_history", self.inner.clear_history(uuid)).await
+    }
+
+    async fn restore_history(&self, uuid: &str, since: &str) -> anyhow::Result<u64> {
+        observe("restore_history", self.inner.restore_history(uuid, since)).await
+    }
+
+    async fn count_trash(&self, before: &str) -> anyhow::Result<i64> {
+        observe("count_trash", self.inner.count_trash(before)).await
+    }
+
+    async fn purge_trash(&self, before: &str) -> anyhow::Result<u64> {
+        observe("purge_trash", self.inner.purge_trash(before)).await
+    }
+
+    async fn search_history(
+        &self,
+        uuid: &str,
+        query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        observe(
+            "search_history",
+            self.inner.search_history(uuid, query, offset, limit),
+        )
+        .await
+    }
+
+    async fn conversation_activity(&self) -> anyhow::Result<Vec<ConversationActivity>> {
+        observe("conversation_activity", self.inner.conversation_activity()).await
+    }
+
+    async fn purge_conversation(&self, uuid: &str, cutoff: &str) -> anyhow::Result<u64> {
+        observe(
+            "purge_conversation",
+            self.inner.purge_conversation(uuid, cutoff),
+        )
+        .await
+    }
+
+    async fn set_pinned(&self, uuid: &str, pinned: bool) -> anyhow::Result<()> {
+        observe("set_pinned", self.inner.set_pinned(uuid, pinned)).await
+    }
+
+    async fn record_purge_run(&self, source: &str, summary: &PurgeSummary) -> anyhow::Result<()> {
+        observe(
+            "record_purge_run",
+            self.inner.record_purge_run(source, summary),
+        )
+        .await
+    }
+
+    async fn list_purge_runs(&self, limit: u32) -> anyhow::Result<Vec<PurgeRun>> {
+        observe("list_purge_runs", self.inner.list_purge_runs(limit)).await
+    }
+
+    async fn scan_contents(&self, after_id: i64, limit: u32) -> anyhow::Result<Vec<StoredContent>> {
+        observe("scan_contents", self.inner.scan_contents(after_id, limit)).await
+    }
+
+    async fn update_contents(&self, contents: &[StoredContent]) -> anyhow::Result<()> {
+        observe("update_contents", self.inner.update_contents(contents)).await
+    }
+
+    async fn any_content_with_prefix(&self, prefix: &str) -> anyhow::Result<bool> {
+        observe(
+            "any_content_with_prefix",
+            self.inner.any_content_with_prefix(prefix),
+        )
+        .await
+    }
+
+    async fn create_user(&self, uuid: &str, role: Role) -> anyhow::Result<()> {
+        observe("create_user", self.inner.create_user(uuid, role)).await
+    }
+
+    async fn find_user(&self, uuid: &str) -> anyhow::Result<Option<User>> {
+        observe("find_user", self.inner.find_user(uuid)).await
+    }
+
+    async fn list_users(&self) -> anyhow::Result<Vec<User>> {
+        observe("list_users", self.inner.list_users()).await
+    }
+
+    async fn count_users_by_role(&self, role: Role) -> anyhow::Result<i64> {
+        observe("count_users_by_role", self.inner.count_users_by_role(role)).await
+    }
+
+    async fn set_user_disabled(&self, uuid: &str, disabled: bool) -> anyhow::Result<bool> {
+        observe(
+            "set_user_disabled",
+            self.inner.set_user_disabled(uuid, disabled),
+        )
+        .await
+    }
+
+    async fn create_api_key(
+        &self,
+        uuid: &str,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+    ) -> anyhow::Result<i64> {
+        observe(
+            "create_api_key",
+            self.inner.create_api_key(uuid, name, key_hash, prefix),
+        )
+        .await
+    }
+
+    async fn list_api_keys(&self, uuid: &str) -> anyhow::Result<Vec<ApiKey>> {
+        observe("list_api_keys", self.inner.list_api_keys(uuid)).await
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> anyhow::Result<Option<ApiKey>> {
+        observe("find_api_key", self.inner.find_api_key(key_hash)).await
+    }
+
+    async fn touch_api_key(&self, id: i64, ip: Option<String>) -> anyhow::Result<()> {
+        observe("touch_api_key", self.inner.touch_api_key(id, ip)).await
+    }
+
+    async fn revoke_api_key(&self, uuid: &str, id: i64) -> anyhow::Result<bool> {
+        observe("revoke_api_key", self.inner.revoke_api_key(uuid, id)).await
+    }
+
+    async fn load_buckets(&self) -> anyhow::Result<HashMap<String, TokenBucket>> {
+        observe("load_buckets", self.inner.load_buckets()).await
+    }
+
+    async fn persist_bucket(&self, key: &str, bucket: &TokenBucket) -> anyhow::Result<()> {
+        observe("persist_bucket", self.inner.persist_bucket(key, bucket)).await
+    }
+
+    async fn delete_idle_buckets(&self, threshold: f64) -> anyhow::Result<u64> {
+        observe(
+            "delete_idle_buckets",
+            self.inner.delete_idle_buckets(threshold),
+        )
+        .await
+    }
+
+    async fn record_usage(
+        &self,
+        uuid: &str,
+        model: &str,
+        usage: &TokenUsage,
+        cost: f64,
+    ) -> anyhow::Result<()> {
+        observe(
+            "record_usage",
+            self.inner.record_usage(uuid, model, usage, cost),
+        )
+        .await
+    }
+
+    async fn sum_usage_since(&self, uuid: &str, period: Period) -> anyhow::Result<TokenUsage> {
+        observe("sum_usage_since", self.inner.sum_usage_since(uuid, period)).await
+    }
+
+    async fn daily_usage(&self, uuid: &str, days: u32) -> anyhow::Result<Vec<DailyUsage>> {
+        observe("daily_usage", self.inner.daily_usage(uuid, days)).await
+    }
+
+    async fn aggregate_cost(&self, group: CostGroup, days: u32) -> anyhow::Result<Vec<CostBucket>> {
+        observe("aggregate_cost", self.inner.aggregate_cost(group, days)).await
+    }
+
+    async fn spent_today(&self, uuid: Option<&str>) -> anyhow::Result<f64> {
+        observe("spent_today", self.inner.spent_today(uuid)).await
+    }
+}
@@ -0,0 +1,806 @@
+/// Persistence, one [Repository] implementation per database backend.
+pub mod encrypted;
+pub mod metered;
+pub mod postgres;
+pub mod sqlite;
+
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr, path::Path};
+
+use async_trait::async_trait;
+use sqlx::{migrate::Migrator, prelude::FromRow};
+use time::OffsetDateTime;
+
+use crate::{
+    MigrateAction,
+    agent::{ChatMessage, HistoryCursor, TokenUsage},
+    auth::{ApiKey, Role, User},
+    config::ServerConfig,
+    cost::{CostBucket, CostGroup},
+    crypto::Keyring,
+    indoc_info, indoc_warn,
+    ratelimit::TokenBucket,
+    retention::{self, ConversationActivity, PurgeRun, PurgeSummary},
+    search::SearchHit,
+    states::{REPOSITORY, server_config},
+    usage::{DailyUsage, Period},
+};
+use encrypted::EncryptedRepository;
+use metered::MeteredRepository;
+use postgres::PostgresRepository;
+use sqlite::SqliteRepository;
+
+/// Storage backend, every query of the server goes through it.
+#[async_trait]
+pub trait Repository: Debug + Send + Sync {
+    /// Bring the schema up to date, called on every startup.
+    async fn migrate(&self) -> anyhow::Result<()>;
+    async fn migration_status(&self) -> anyhow::Result<Vec<MigrationInfo>>;
+    /// Revert migrations newer than target, one step back if `None`,
+    /// returns the version reverted to.
+    async fn migrate_down(&self, target: Option<i64>) -> anyhow::Result<i64>;
+    /// Write a consistent copy of the database to dest, which must not exist,
+    /// while the server keeps running.
+    async fn snapshot(&self, dest: &Path) -> anyhow::Result<()>;
+    /// Cheapest round trip to the database, for readiness probes.
+    async fn ping(&self) -> anyhow::Result<()>;
+
+    /// Whole history of uuid, oldest first.
+    async fn load_history(&self, uuid: &str) -> anyhow::Result<Vec<ChatMessage>>;
+    /// Up to limit messages of uuid next to the cursor, oldest first.
+    async fn load_history_page(
+        &self,
+        uuid: &str,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>>;
+    /// Returns id and time of the new row.
+    async fn persist_message(&self, message: &ChatMessage) -> anyhow::Result<(i64, String)>;
+    /// Insert all or none of the messages, keeping their time.
+    async fn import_messages(&self, messages: &[ChatMessage]) -> anyhow::Result<()>;
+    /// Uuid of every conversation with history.
+    async fn list_conversations(&self) -> anyhow::Result<Vec<String>>;
+    /// Move live history of uuid to the trash, returns rows moved.
+    async fn clear_history(&self, uuid: &str) -> anyhow::Result<u64>;
+    /// Bring back messages of uuid trashed at or after since, returns rows restored.
+    async fn restore_history(&self, uuid: &str, since: &str) -> anyhow::Result<u64>;
+    /// Messages trashed before the given time, of every user.
+    async fn count_trash(&self, before: &str) -> anyhow::Result<i64>;
+    /// Delete messages trashed before the given time, returns rows removed.
+    async fn purge_trash(&self, before: &str) -> anyhow::Result<u64>;
+    /// Messages of uuid matching every word of query, best matches first.
+    async fn search_history(
+        &self,
+        uuid: &str,
+        query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SearchHit>>;
+    /// Owner role, last message time, message count and pin of every conversation.
+    async fn conversation_activity(&self) -> anyhow::Result<Vec<ConversationActivity>>;
+    /// Delete history of uuid unless pinned or active at or after cutoff, returns rows removed.
+    async fn purge_conversation(&self, uuid: &str, cutoff: &str) -> anyhow::Result<u64>;
+    /// Pinned conversations are never purged by retention.
+    async fn set_pinned(&self, uuid: &str, pinned: bool) -> anyhow::Result<()>;
+    async fn record_purge_run(&self, source: &str, summary: &PurgeSummary) -> anyhow::Result<()>;
+    /// Most recent purge runs first.
+    async fn list_purge_runs(&self, limit: u32) -> anyhow::Result<Vec<PurgeRun>>;
+    /// Up to limit messages with id above `after_id` in id order, content as stored.
+    async fn scan_contents(&self, after_id: i64, limit: u32) -> anyhow::Result<Vec<StoredContent>>;
+    /// Overwrite content of messages by id, all or none.
+    async fn update_contents(&self, contents: &[StoredContent]) -> anyhow::Result<()>;
+    /// Whether any message content, trashed ones included, starts with prefix,
+    /// false while the schema does not exist yet.
+    async fn any_content_with_prefix(&self, prefix: &str) -> anyhow::Result<bool>;
+
+    async fn create_user(&self, uuid: &str, role: Role) -> anyhow::Result<()>;
+    async fn find_user(&self, uuid: &str) -> anyhow::Result<Option<User>>;
+    async fn list_users(&self) -> anyhow::Result<Vec<User>>;
+    async fn count_users_by_role(&self, role: Role) -> anyhow::Result<i64>;
+    /// Disable or enable user, returns false if there is no such user.
+    async fn set_user_disabled(&self, uuid: &str, disabled: bool) -> anyhow::Result<bool>;
+
+    /// Store a newly generated key by its hash, returns the row id.
+    async fn create_api_key(
+        &self,
+        uuid: &str,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+    ) -> anyhow::Result<i64>;
+    async fn list_api_keys(&self, uuid: &str) -> anyhow::Result<Vec<ApiKey>>;
+    async fn find_api_key(&self, key_hash: &str) -> anyhow::Result<Option<ApiKey>>;
+    async fn touch_api_key(&self, id: i64, ip: Option<String>) -> anyhow::Result<()>;
+    async fn revoke_api_key(&self, uuid: &str, id: i64) -> anyhow::Result<bool>;
+
+    async fn load_buckets(&self) -> anyhow::Result<HashMap<String, TokenBucket>>;
+    async fn persist_bucket(&self, key: &str, bucket: &TokenBucket) -> anyhow::Result<()>;
+    async fn delete_idle_buckets(&self, threshold: f64) -> anyhow::Result<u64>;
+
+    async fn record_usage(
+        &self,
+        uuid: &str,
+        model: &str,
+        usage: &TokenUsage,
+        cost: f64,
+    ) -> anyhow::Result<()>;
+    /// Usage of the user since start of the current UTC day or month.
+    async fn sum_usage_since(&self, uuid: &str, period: Period) -> anyhow::Result<TokenUsage>;
+    /// Per day usage of the last `days` days, oldest first.
+    async fn daily_usage(&self, uuid: &str, days: u32) -> anyhow::Result<Vec<DailyUsage>>;
+    /// Consumption of the last `days` days grouped by user, model or day, costliest first.
+    async fn aggregate_cost(&self, group: CostGroup, days: u32) -> anyhow::Result<Vec<CostBucket>>;
+    /// Spend since start of the UTC day, of one user or everyone.
+    async fn spent_today(&self, uuid: Option<&str>) -> anyhow::Result<f64>;
+}
+
+#[derive(Debug)]
+pub struct MigrationInfo {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+impl MigrationInfo {
+    fn list(migrator: &Migrator, applied: &[i64]) -> Vec<Self> {
+        migrator
+            .iter()
+            .filter(|m| m.migration_type.is_up_migration())
+            .map(|m| Self {
+                version: m.version,
+                description: m.description.to_string(),
+                applied: applied.contains(&m.version),
+            })
+            .collect()
+    }
+
+    /// Version before the latest applied one, 0 if none.
+    fn previous(applied: &[i64]) -> i64 {
+        applied.iter().rev().nth(1).copied().unwrap_or(0)
+    }
+}
+
+impl HistoryCursor {
+    /// Condition on `id` against the bound id, and whether to scan newest first.
+    fn condition(self) -> (&'static str, i64, bool) {
+        match self {
+            Self::Latest => ("id < $2", i64::MAX, true),
+            Self::Before(id) => ("id < $2", id, true),
+            Self::After(id) => ("id > $2", id, false),
+        }
+    }
+}
+
+/// Message content exactly as stored, encrypted or not.
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct StoredContent {
+    pub id: i64,
+    pub uuid: String,
+    pub message: String,
+}
+
+#[derive(FromRow)]
+struct KeyedBucket {
+    key: String,
+    #[sqlx(flatten)]
+    bucket: TokenBucket,
+}
+
+/// Connect to the backend selected by `database_url`,
+/// behind [EncryptedRepository] if message encryption is enabled.
+pub async fn init_repository(
+    config: &ServerConfig,
+    data_dir: &Path,
+) -> anyhow::Result<Box<dyn Repository>> {
+    let backend: Box<dyn Repository> = match config.database_url {
+        Some(ref url) if url.starts_with("postgres") => {
+            Box::new(PostgresRepository::connect(url, config.db_pool_size).await?)
+        }
+        Some(ref url) => anyhow::bail!("unsupported database_url {url}"),
+        None => {
+            Box::new(SqliteRepository::open(&data_dir.join("store.db"), config.db_pool_size).await?)
+        }
+    };
+    // timed below encryption, to measure the database alone
+    let backend = Box::new(MeteredRepository::new(backend));
+    if !config.encryption.enabled {
+        return Ok(backend);
+    }
+    let keyring = Keyring::load(&config.encryption, data_dir, backend.as_ref()).await?;
+    Ok(Box::new(EncryptedRepository::new(backend, keyring)))
+}
+
+pub fn repo() -> &'static dyn Repository {
+    REPOSITORY.get().unwrap().as_ref()
+}
+
+/// Log failure and fall back, a broken query must not take a request down.
+fn or_warn<T>(res: anyhow::Result<T>, action: &str, fallback: T) -> T {
+    match res {
+        Ok(value) => value,
+        Err(e) => {
+            indoc_warn!(
+                "
+                {action} failed, error:
+                {e:#}
+                "
+            );
+            fallback
+        }
+    }
+}
+
+pub async fn migrate() -> anyhow::Result<()> {
+    repo().migrate().await?;
+    indoc_info!("DB schema up to date.");
+    Ok(())
+}
+
+/// Run the `migrate` subcommand against the database of this instance.
+pub async fn migrate_command(action: MigrateAction) -> anyhow::Result<()> {
+    match action {
+        MigrateAction::Status => {
+            for migration in repo().migration_status().await? {
+                let state = if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!(
+                    "{} {} [{}]",
+                    migration.version, migration.description, state
+                );
+            }
+        }
+        MigrateAction::Up => migrate().await?,
+        MigrateAction::Down { target } => {
+            let target = repo().migrate_down(target).await?;
+            indoc_info!("DB schema reverted to version {target}.");
+        }
+    }
+    Ok(())
+}
+
+pub async fn clear_history_by_uuid(uuid: &str) {
+    or_warn(
+        repo().clear_history(uuid).await,
+        "Clear chat history by uuid",
+        0,
+    );
+}
+
+/// Restore history of uuid cleared within the trash window, returns messages restored.
+pub async fn restore_history_by_uuid(uuid: &str) -> u64 {
+    let config = server_config();
+    let since = retention::trash_cutoff(&config, OffsetDateTime::now_utc());
+    or_warn(
+        repo().restore_history(uuid, &since).await,
+        "Restore chat history by uuid",
+        0,
+    )
+}
+
+pub async fn set_pinned(uuid: &str, pinned: bool) {
+    or_warn(
+        repo().set_pinned(uuid, pinned).await,
+        "Set conversation pin",
+        (),
+    );
+}
+
+impl PurgeRun {
+    /// Most recent first.
+    pub async fn list(limit: u32) -> Vec<Self> {
+        or_warn(
+            repo().list_purge_runs(limit).await,
+            "Query purge runs",
+            Vec::new(),
+        )
+    }
+}
+
+impl ChatMessage {
+    /// `None` on failure, which must not pass for an empty history.
+    pub async fn load_all(uuid: &str) -> Option<Vec<Self>> {
+        or_warn(
+            repo().load_history(uuid).await.map(Some),
+            "Query chat history",
+            None,
+        )
+    }
+
+    pub async fn load_page(uuid: &str, cursor: HistoryCursor, limit: u32) -> Option<Vec<Self>> {
+        or_warn(
+            repo()
+                .load_history_page(uuid, cursor, limit)
+                .await
+                .map(Some),
+            "Query chat history page",
+            None,
+        )
+    }
+
+    /// Insert the message, then fill in its id and time.
+    pub async fn persist(&mut self) {
+        let res = repo().persist_message(self).await;
+        if let Some((id, time)) = or_warn(res.map(Some), "Insert chat history", None) {
+            self.id = id;
+            self.time = time;
+        }
+    }
+}
+
+impl SearchHit {
+    pub async fn find(uuid: &str, query: &str, offset: u32, limit: u32) -> Vec<Self> {
+        or_warn(
+            repo().search_history(uuid, query, offset, limit).await,
+            "Search chat history",
+            Vec::new(),
+        )
+    }
+}
+
+impl ApiKey {
+    /// Store a newly generated key by its hash, returns the row id.
+    pub async fn create(uuid: &str, name: &str, key_hash: &str, prefix: &str) -> Option<i64> {
+        let res = repo().create_api_key(uuid, name, key_hash, prefix).await;
+        or_warn(res.map(Some), "Insert api key", None)
+    }
+
+    pub async fn list_active(uuid: &str) -> Vec<Self> {
+        or_warn(
+            repo().list_api_keys(uuid).await,
+            "Query api keys",
+            Vec::new(),
+        )
+    }
+
+    pub async fn find_active(key_hash: &str) -> Option<Self> {
+        or_warn(
+            repo().find_api_key(key_hash).await,
+            "Query api key by hash",
+            None,
+        )
+    }
+
+    /// Record last used time and client ip.
+    pub async fn touch(id: i64, ip: Option<SocketAddr>) {
+        let ip = ip.map(|addr| addr.ip().to_string());
+        or_warn(
+            repo().touch_api_key(id, ip).await,
+            "Update api key usage",
+            (),
+        );
+    }
+
+    /// Revoke a key owned by uuid, returns false if there is no such key.
+    pub async fn revoke(uuid: &str, id: i64) -> bool {
+        or_warn(
+            repo().revoke_api_key(uuid, id).await,
+            "Revoke api key",
+            false,
+        )
+    }
+}
+
+impl User {
+    /// Returns false if the user could not be stored.
+    pub async fn create(uuid: &str, role: Role) -> bool {
+        let res = repo().create_user(uuid, role).await;
+        or_warn(res.map(|_| true), "Insert user", false)
+    }
+
+    pub async fn find(uuid: &str) -> Option<Self> {
+        or_warn(repo().find_user(uuid).await, "Query user", None)
+    }
+
+    pub async fn list_all() -> Vec<Self> {
+        or_warn(repo().list_users().await, "Query users", Vec::new())
+    }
+}
+
+impl TokenBucket {
+    pub async fn load_all() -> HashMap<String, Self> {
+        or_warn(
+            repo().load_buckets().await,
+            "Query rate limit buckets",
+            HashMap::new(),
+        )
+    }
+
+    pub async fn persist(&self, key: &str) {
+        or_warn(
+            repo().persist_bucket(key, self).await,
+            "Upsert rate limit bucket",
+            (),
+        );
+    }
+
+    pub async fn delete_idle(threshold: f64) {
+        or_warn(
+            repo().delete_idle_buckets(threshold).await,
+            "Delete idle rate limit buckets",
+            0,
+        );
+    }
+}
+
+impl TokenUsage {
+    /// Account usage to the user, independent of chat history lifetime.
+    pub async fn record(&self, uuid: &str, model: &str, cost: f64) {
+        or_warn(
+            repo().record_usage(uuid, model, self, cost).await,
+            "Insert token usage",
+            (),
+        );
+    }
+
+    /// Usage of the user since start of the current UTC day or month.
+    pub async fn sum_since(uuid: &str, period: Period) -> Self {
+        or_warn(
+            repo().sum_usage_since(uuid, period).await,
+            "Sum token usage",
+            Self::default(),
+        )
+    }
+}
+
+impl DailyUsage {
+    /// Per day usage of the last `days` days, oldest first.
+    pub async fn load(uuid: &str, days: u32) -> Vec<Self> {
+        or_warn(
+            repo().daily_usage(uuid, days).await,
+            "Query daily usage",
+            Vec::new(),
+        )
+    }
+}
+
+impl CostBucket {
+    /// Consumption of the last `days` days grouped by user, model or day, costliest first.
+    pub async fn aggregate(group: CostGroup, days: u32) -> Vec<Self> {
+        or_warn(
+            repo().aggregate_cost(group, days).await,
+            "Aggregate cost",
+            Vec::new(),
+        )
+    }
+
+    /// Spend since start of the UTC day, of one user or everyone.
+    pub async fn spent_today(uuid: Option<&str>) -> f64 {
+        or_warn(repo().spent_today(uuid).await, "Sum spend", 0.0)
+    }
+}
+
+#[allow(unused)]
+pub(crate) mod test {
+    use super::*;
+    use crate::agent::{AgentReply, MessageStatus};
+
+    pub async fn memory_repository() -> SqliteRepository {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        SqliteRepository::with_pool(pool)
+    }
+
+    /// Session whose message predates versioned migrations in [global_repository].
+    pub const LEGACY_UUID: &str = "legacy-session";
+
+    /// In-memory repository behind [repo], shared by every test going through it.
+    /// Upgraded from a legacy database holding a message of [LEGACY_UUID], other tests must
+    /// work on their own uuids.
+    pub async fn global_repository() -> &'static dyn Repository {
+        if REPOSITORY.get().is_none() {
+            let repo = sqlite::test::legacy_repository(LEGACY_UUID).await;
+            repo.migrate().await.unwrap();
+            // another test may have set it meanwhile, which serves as well
+            let _ = REPOSITORY.set(Box::new(repo));
+        }
+        repo()
+    }
+
+    /// Behaviour every backend must share, run against a migrated empty database.
+    pub async fn exercise_repository(repo: &dyn Repository) {
+        repo.migrate().await.unwrap();
+        repo.ping().await.unwrap();
+        // keys unique per run, the postgres database may be reused
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let other = uuid::Uuid::new_v4().to_string();
+
+        // chat history
+        let usage = TokenUsage {
+            prompt_tokens: 3,
+            completion_tokens: 5,
+        };
+        let question = ChatMessage::create_user(&uuid, "hi");
+        let reply = AgentReply {
+            content: "hello".into(),
+            usage,
+            model: "model-a".into(),
+            latency_ms: 42,
+            finish_reason: Some("stop".into()),
+        };
+        let answer = ChatMessage::create_assistant(&uuid, &reply);
+        let (question_id, time) = repo.persist_message(&question).await.unwrap();
+        let (answer_id, _) = repo.persist_message(&answer).await.unwrap();
+        assert!(answer_id > question_id);
+        assert_eq!(time.len(), "2025-03-01 00:00:00".len());
+        let history = repo.load_history(&uuid).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].content, "hi");
+        assert_eq!(history[1].usage, usage);
+        assert_eq!((history[0].id, history[1].id), (question_id, answer_id));
+        assert_eq!(history[0].time, time);
+        assert_eq!(history[0].model, None);
+        assert_eq!(history[1].model.as_deref(), Some("model-a"));
+        assert_eq!(history[1].latency_ms, Some(42));
+        assert_eq!(history[1].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(history[1].get_status(), MessageStatus::Ok);
+        let failed = ChatMessage::create_failed(&other, "model-a", 7, MessageStatus::Error);
+        repo.persist_message(&failed).await.unwrap();
+        let history = repo.load_history(&other).await.unwrap();
+        assert_eq!(history[0].get_status(), MessageStatus::Error);
+        assert_eq!(history[0].finish_reason, None);
+        repo.clear_history(&other).await.unwrap();
+        assert!(repo.load_history(&other).await.unwrap().is_empty());
+
+        // search is scoped to the caller
+        let secret = ChatMessage::create_user(&other, "my regex password is hunter2");
+        repo.persist_message(&secret).await.unwrap();
+        let reply = AgentReply {
+            content: "use a regex like ^a+$ to match".into(),
+            ..reply
+        };
+        let regex = ChatMessage::create_assistant(&uuid, &reply);
+        repo.persist_message(&regex).await.unwrap();
+        let hits = repo.search_history(&uuid, "regex", 0, 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].snippet.contains("<mark>regex</mark>"));
+        assert!(!hits[0].time.is_empty());
+        for query in ["hunter2", "password", "regex password"] {
+            let hits = repo.search_history(&uuid, query, 0, 10).await.unwrap();
+            assert!(hits.is_empty(), "{query}");
+        }
+        let hits = repo.search_history(&other, "hunter2", 0, 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        let hits = repo.search_history(&uuid, "regex", 1, 10).await.unwrap();
+        assert!(hits.is_empty());
+        // snippets are rendered as HTML, only the marks may be markup
+        let script = ChatMessage::create_user(
+            &other,
+            "alert <script>steal()</script> <img src=x onerror=f()>",
+        );
+        repo.persist_message(&script).await.unwrap();
+        let hits = repo.search_history(&other, "alert", 0, 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        let stripped = hits[0]
+            .snippet
+            .replace(crate::search::MARK_START, "")
+            .replace(crate::search::MARK_END, "");
+        assert!(!stripped.contains('<'), "{}", hits[0].snippet);
+        assert!(hits[0].snippet.contains("&lt;img"));
+        assert!(hits[0].snippet.contains("<mark>alert</mark>"));
+        assert_eq!(repo.clear_history(&other).await.unwrap(), 2);
+        assert!(
+            repo.search_history(&other, "hunter2", 0, 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // retention never purges recent or pinned conversations
+        let activity = repo.conversation_activity().await.unwrap();
+        let mine = activity.iter().find(|a| a.uuid == uuid).unwrap();
+        assert_eq!(
+            (mine.messages, mine.pinned, mine.role.as_deref()),
+            (3, false, None)
+        );
+        assert_eq!(mine.last_active.len(), time.len());
+        let future = "9999-01-01 00:00:00";
+        assert_eq!(
+            repo.purge_conversation(&uuid, &mine.last_active)
+                .await
+                .unwrap(),
+            0
+        );
+        repo.set_pinned(&uuid, true).await.unwrap();
+        repo.set_pinned(&uuid, true).await.unwrap();
+        let activity = repo.conversation_activity().await.unwrap();
+        assert!(activity.iter().any(|a| a.uuid == uuid && a.pinned));
+        assert_eq!(repo.purge_conversation(&uuid, future).await.unwrap(), 0);
+        repo.set_pinned(&uuid, false).await.unwrap();
+        let summary = PurgeSummary {
+            conversations: 1,
+            messages: 2,
+            pinned_kept: 0,
+            trash_messages: 3,
+        };
+        repo.record_purge_run("exercise", &summary).await.unwrap();
+        let runs = repo.list_purge_runs(1).await.unwrap();
+        assert_eq!(
+            (runs[0].source.as_str(), runs[0].summary),
+            ("exercise", summary)
+        );
+        assert_eq!(repo.clear_history(&uuid).await.unwrap(), 3);
+        assert!(repo.load_history(&uuid).await.unwrap().is_empty());
+        assert!(
+            repo.search_history(&uuid, "regex", 0, 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // cleared history is trashed until restored or purged
+        assert!(!repo.list_conversations().await.unwrap().contains(&uuid));
+        let activity = repo.conversation_activity().await.unwrap();
+        assert!(!activity.iter().any(|a| a.uuid == uuid));
+        assert!(repo.count_trash(future).await.unwrap() >= 3);
+        assert_eq!(repo.restore_history(&uuid, future).await.unwrap(), 0);
+        let epoch = "1970-01-01 00:00:00";
+        assert_eq!(repo.restore_history(&uuid, epoch).await.unwrap(), 3);
+        assert_eq!(repo.load_history(&uuid).await.unwrap().len(), 3);
+        assert_eq!(repo.clear_history(&uuid).await.unwrap(), 3);
+        assert_eq!(repo.count_trash(epoch).await.unwrap(), 0);
+        assert_eq!(repo.purge_trash(epoch).await.unwrap(), 0);
+        assert!(repo.purge_trash(future).await.unwrap() >= 3);
+        assert_eq!(repo.restore_history(&uuid, epoch).await.unwrap(), 0);
+
+        // cursor pagination, pages are oldest first
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let message = ChatMessage::create_user(&other, &i.to_string());
+            ids.push(repo.persist_message(&message).await.unwrap().0);
+        }
+        let page_ids = |page: Vec<ChatMessage>| page.iter().map(|m| m.id).collect::<Vec<_>>();
+        let latest = repo
+            .load_history_page(&other, HistoryCursor::Latest, 2)
+            .await
+            .unwrap();
+        assert_eq!(latest[1].content, "4");
+        assert_eq!(page_ids(latest), ids[3..]);
+        let older = repo
+            .load_history_page(&other, HistoryCursor::Before(ids[3]), 2)
+            .await
+            .unwrap();
+        assert_eq!(page_ids(older), ids[1..3]);
+        let oldest = repo
+            .load_history_page(&other, HistoryCursor::Before(ids[1]), 2)
+            .await
+            .unwrap();
+        assert_eq!(page_ids(oldest), ids[..1]);
+        let newer = repo
+            .load_history_page(&other, HistoryCursor::After(ids[0]), 3)
+            .await
+            .unwrap();
+        assert_eq!(page_ids(newer), ids[1..4]);
+        assert!(
+            repo.load_history_page(&uuid, HistoryCursor::After(ids[0]), 3)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // raw content access for key rotation
+        let stored = repo.scan_contents(ids[0] - 1, 2).await.unwrap();
+        assert_eq!(stored.iter().map(|c| c.id).collect::<Vec<_>>(), ids[..2]);
+        assert_eq!(stored[0].uuid, other);
+        let rewritten = StoredContent {
+            message: "rewritten".into(),
+            ..stored[1].clone()
+        };
+        repo.update_contents(&[rewritten]).await.unwrap();
+        let history = repo.load_history(&other).await.unwrap();
+        assert_eq!(history[1].content, "rewritten");
+        assert_eq!(history[2].content, "2");
+        repo.clear_history(&other).await.unwrap();
+
+        // import keeps time
+        let mut old = ChatMessage::create_user(&other, "imported");
+        old.time = "2024-01-02 03:04:05".into();
+        repo.import_messages(&[old]).await.unwrap();
+        let history = repo.load_history(&other).await.unwrap();
+        assert_eq!(history[0].time, "2024-01-02 03:04:05");
+        assert!(repo.list_conversations().await.unwrap().contains(&other));
+        repo.clear_history(&other).await.unwrap();
+
+        // users
+        repo.create_user(&uuid, Role::Admin).await.unwrap();
+        let admins = repo.count_users_by_role(Role::Admin).await.unwrap();
+        assert!(admins >= 1);
+        let user = repo.find_user(&uuid).await.unwrap().unwrap();
+        assert_eq!(user.get_role(), Role::Admin);
+        assert!(!user.created_at.is_empty());
+        assert!(user.disabled_at.is_none());
+        assert!(repo.find_user(&other).await.unwrap().is_none());
+        assert!(repo.set_user_disabled(&uuid, true).await.unwrap());
+        let disabled_at = repo.find_user(&uuid).await.unwrap().unwrap().disabled_at;
+        assert!(disabled_at.is_some());
+        // disabling again keeps the first time
+        repo.set_user_disabled(&uuid, true).await.unwrap();
+        let user = repo.find_user(&uuid).await.unwrap().unwrap();
+        assert_eq!(user.disabled_at, disabled_at);
+        assert!(repo.set_user_disabled(&uuid, false).await.unwrap());
+        assert!(
+            repo.find_user(&uuid)
+                .await
+                .unwrap()
+                .unwrap()
+                .disabled_at
+                .is_none()
+        );
+        assert!(!repo.set_user_disabled(&other, true).await.unwrap());
+        assert!(
+            repo.list_users()
+                .await
+                .unwrap()
+                .iter()
+                .any(|u| u.uuid == uuid)
+        );
+
+        // api keys
+        let hash = format!("hash-{uuid}");
+        let id = repo
+            .create_api_key(&uuid, "ci", &hash, "awk_1234")
+            .await
+            .unwrap();
+        let key = repo.find_api_key(&hash).await.unwrap().unwrap();
+        assert_eq!((key.id, key.uuid.as_str()), (id, uuid.as_str()));
+        assert!(key.last_used_at.is_none());
+        repo.touch_api_key(id, Some("127.0.0.1".into()))
+            .await
+            .unwrap();
+        let keys = repo.list_api_keys(&uuid).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].last_used_at.is_some());
+        assert_eq!(keys[0].last_used_ip.as_deref(), Some("127.0.0.1"));
+        assert!(!repo.revoke_api_key(&other, id).await.unwrap());
+        assert!(repo.revoke_api_key(&uuid, id).await.unwrap());
+        assert!(repo.find_api_key(&hash).await.unwrap().is_none());
+
+        // rate limit buckets
+        let key = format!("/ask-agent|user|{uuid}");
+        let bucket = TokenBucket {
+            tokens: 2.0,
+            updated_at: 100.0,
+        };
+        repo.persist_bucket(&key, &bucket).await.unwrap();
+        let bucket = TokenBucket {
+            tokens: 1.0,
+            updated_at: 200.0,
+        };
+        repo.persist_bucket(&key, &bucket).await.unwrap();
+        assert_eq!(repo.load_buckets().await.unwrap()[&key].tokens, 1.0);
+        assert!(repo.delete_idle_buckets(300.0).await.unwrap() >= 1);
+        assert!(!repo.load_buckets().await.unwrap().contains_key(&key));
+
+        // usage and cost
+        repo.record_usage(&uuid, "model-a", &usage, 0.5)
+            .await
+            .unwrap();
+        repo.record_usage(&uuid, "model-b", &usage, 0.25)
+            .await
+            .unwrap();
+        for period in [Period::Day, Period::Month] {
+            let sum = repo.sum_usage_since(&uuid, period).await.unwrap();
+            assert_eq!(sum.total(), 2 * usage.total());
+        }
+        let daily = repo.daily_usage(&uuid, 7).await.unwrap();
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].completion_tokens, 10);
+        assert_eq!(
+            repo.sum_usage_since(&other, Period::Day).await.unwrap(),
+            TokenUsage::default()
+        );
+        assert!((repo.spent_today(Some(&uuid)).await.unwrap() - 0.75).abs() < 1e-9);
+        assert!(repo.spent_today(None).await.unwrap() >= 0.75);
+        let by_user = repo.aggregate_cost(CostGroup::User, 1).await.unwrap();
+        let mine = by_user.iter().find(|b| b.key == uuid).unwrap();
+        assert_eq!(mine.prompt_tokens, 6);
+        let by_day = repo.aggregate_cost(CostGroup::Day, 1).await.unwrap();
+        assert!(!by_day.is_empty());
+        let by_model = repo.aggregate_cost(CostGroup::Model, 1).await.unwrap();
+        assert!(by_model.iter().any(|b| b.key == "model-a"));
+    }
+}
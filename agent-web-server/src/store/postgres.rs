@@ -0,0 +1,845 @@
+/// PostgreSQL backend, for several replicas sharing one database.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use indoc::{formatdoc, indoc};
+use sqlx::{
+    PgPool,
+    migrate::{Migrate, Migrator},
+};
+
+use super::{KeyedBucket, MigrationInfo, Repository, StoredContent};
+use crate::{
+    agent::{ChatMessage, HistoryCursor, TokenUsage},
+    auth::{ApiKey, Role, User},
+    cost::{CostBucket, CostGroup},
+    indoc_info,
+    ratelimit::TokenBucket,
+    retention::{ConversationActivity, PurgeRun, PurgeSummary},
+    search::{DB_MARK_END, DB_MARK_START, SearchHit},
+    usage::{DailyUsage, Period},
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+#[derive(Debug)]
+pub struct PostgresRepository {
+    pool: PgPool,
+}
+
+impl PostgresRepository {
+    pub async fn connect(db_url: &str, max_conn: u32) -> anyhow::Result<Self> {
+        indoc_info!("Building DB connection pool to PostgreSQL...");
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(max_conn)
+            .connect(db_url)
+            .await?;
+        indoc_info!("DB init complete.");
+        Ok(Self { pool })
+    }
+
+    async fn applied_versions(&self) -> anyhow::Result<Vec<i64>> {
+        let mut conn = self.pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        let mut versions: Vec<i64> = conn
+            .list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|m| m.version)
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+}
+
+#[async_trait]
+impl Repository for PostgresRepository {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .with_context(|| "run migrations")?;
+        Ok(())
+    }
+
+    async fn migration_status(&self) -> anyhow::Result<Vec<MigrationInfo>> {
+        let applied = self.applied_versions().await?;
+        Ok(MigrationInfo::list(&MIGRATOR, &applied))
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1;").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn migrate_down(&self, target: Option<i64>) -> anyhow::Result<i64> {
+        let applied = self.applied_versions().await?;
+        let target = target.unwrap_or_else(|| MigrationInfo::previous(&applied));
+        MIGRATOR
+            .undo(&self.pool, target)
+            .await
+            .with_context(|| "revert migrations")?;
+        Ok(target)
+    }
+
+    async fn snapshot(&self, _dest: &Path) -> anyhow::Result<()> {
+        anyhow::bail!("PostgreSQL can't be snapshotted by the server, use pg_dump")
+    }
+
+    async fn load_history(&self, uuid: &str) -> anyhow::Result<Vec<ChatMessage>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status,
+                to_char(time, 'YYYY-MM-DD HH24:MI:SS') AS time
+            FROM chat_history
+            WHERE uuid = $1 AND deleted_at IS NULL
+            ORDER BY id ASC;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn load_history_page(
+        &self,
+        uuid: &str,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let (condition, id, newest_first) = cursor.condition();
+        let order = if newest_first { "DESC" } else { "ASC" };
+        let query = formatdoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status,
+                to_char(time, 'YYYY-MM-DD HH24:MI:SS') AS time
+            FROM chat_history
+            WHERE uuid = $1 AND deleted_at IS NULL AND {condition}
+            ORDER BY id {order}
+            LIMIT $3;
+            "
+        );
+        let mut page: Vec<ChatMessage> = sqlx::query_as(&query)
+            .bind(uuid)
+            .bind(id)
+            .bind(i64::from(limit))
+            .fetch_all(&self.pool)
+            .await?;
+        if newest_first {
+            page.reverse();
+        }
+        Ok(page)
+    }
+
+    async fn persist_message(&self, message: &ChatMessage) -> anyhow::Result<(i64, String)> {
+        let query = indoc!(
+            "
+            INSERT INTO chat_history (
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, to_char(time, 'YYYY-MM-DD HH24:MI:SS');
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(&message.uuid)
+            .bind(&message.content)
+            .bind(&message.role)
+            .bind(message.usage.prompt_tokens)
+            .bind(message.usage.completion_tokens)
+            .bind(&message.model)
+            .bind(message.latency_ms)
+            .bind(&message.finish_reason)
+            .bind(&message.status)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn import_messages(&self, messages: &[ChatMessage]) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO chat_history (
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status,
+                time
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, CAST($10 AS TIMESTAMP));
+            "
+        );
+        let mut tx = self.pool.begin().await?;
+        for message in messages {
+            sqlx::query(query)
+                .bind(&message.uuid)
+                .bind(&message.content)
+                .bind(&message.role)
+                .bind(message.usage.prompt_tokens)
+                .bind(message.usage.completion_tokens)
+                .bind(&message.model)
+                .bind(message.latency_ms)
+                .bind(&message.finish_reason)
+                .bind(&message.status)
+                .bind(&message.time)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_conversations(&self) -> anyhow::Result<Vec<String>> {
+        let query = indoc!(
+            "
+            SELECT DISTINCT uuid
+            FROM chat_history
+            WHERE deleted_at IS NULL
+            ORDER BY uuid ASC;
+            "
+        );
+        Ok(sqlx::query_scalar(query).fetch_all(&self.pool).await?)
+    }
+
+    async fn scan_contents(&self, after_id: i64, limit: u32) -> anyhow::Result<Vec<StoredContent>> {
+        let query = indoc!(
+            "
+            SELECT id, uuid, message
+            FROM chat_history
+            WHERE id > $1
+            ORDER BY id ASC
+            LIMIT $2;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(after_id)
+            .bind(i64::from(limit))
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn update_contents(&self, contents: &[StoredContent]) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            UPDATE chat_history
+            SET message = $1
+            WHERE id = $2;
+            "
+        );
+        let mut tx = self.pool.begin().await?;
+        for content in contents {
+            sqlx::query(query)
+                .bind(&content.message)
+                .bind(content.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn any_content_with_prefix(&self, prefix: &str) -> anyhow::Result<bool> {
+        let table = "SELECT to_regclass('chat_history') IS NOT NULL;";
+        if !sqlx::query_scalar::<_, bool>(table)
+            .fetch_one(&self.pool)
+            .await?
+        {
+            return Ok(false);
+        }
+        let query = indoc!(
+            "
+            SELECT EXISTS(
+                SELECT 1 FROM chat_history WHERE starts_with(message, $1)
+            );
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(prefix)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn clear_history(&self, uuid: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            UPDATE chat_history
+            SET deleted_at = (NOW() AT TIME ZONE 'UTC')
+            WHERE uuid = $1 AND deleted_at IS NULL;
+            "
+        );
+        let r = sqlx::query(query).bind(uuid).execute(&self.pool).await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn restore_history(&self, uuid: &str, since: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            UPDATE chat_history
+            SET deleted_at = NULL
+            WHERE uuid = $1 AND deleted_at >= CAST($2 AS TIMESTAMP);
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(uuid)
+            .bind(since)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn count_trash(&self, before: &str) -> anyhow::Result<i64> {
+        let query = indoc!(
+            "
+            SELECT COUNT(*)
+            FROM chat_history
+            WHERE deleted_at < CAST($1 AS TIMESTAMP);
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(before)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn purge_trash(&self, before: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            DELETE FROM chat_history
+            WHERE deleted_at < CAST($1 AS TIMESTAMP);
+            "
+        );
+        let r = sqlx::query(query).bind(before).execute(&self.pool).await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn search_history(
+        &self,
+        uuid: &str,
+        query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        // the tsvector expression matches idx_chat_history_fts
+        let query_sql = formatdoc!(
+            "
+            SELECT
+                id,
+                role,
+                ts_headline(
+                    'simple',
+                    COALESCE(message, ''),
+                    plainto_tsquery('simple', $1),
+                    'StartSel={DB_MARK_START}, StopSel={DB_MARK_END}, MaxWords=16, MinWords=4'
+                ) AS snippet,
+                to_char(time, 'YYYY-MM-DD HH24:MI:SS') AS time
+            FROM chat_history
+            WHERE uuid = $2
+                AND deleted_at IS NULL
+                AND to_tsvector('simple', COALESCE(message, '')) @@ plainto_tsquery('simple', $1)
+            ORDER BY
+                ts_rank(to_tsvector('simple', COALESCE(message, '')), plainto_tsquery('simple', $1)) DESC,
+                id DESC
+            LIMIT $3 OFFSET $4;
+            "
+        );
+        Ok(sqlx::query_as(&query_sql)
+            .bind(query)
+            .bind(uuid)
+            .bind(i64::from(limit))
+            .bind(i64::from(offset))
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(SearchHit::escape_db_snippet)
+            .collect())
+    }
+
+    async fn conversation_activity(&self) -> anyhow::Result<Vec<ConversationActivity>> {
+        let query = indoc!(
+            r#"
+            SELECT
+                chat_history.uuid AS uuid,
+                "user".role AS role,
+                to_char(MAX(chat_history.time), 'YYYY-MM-DD HH24:MI:SS') AS last_active,
+                COUNT(*) AS messages,
+                pinned_conversation.uuid IS NOT NULL AS pinned
+            FROM chat_history
+            LEFT JOIN "user" ON "user".uuid = chat_history.uuid
+            LEFT JOIN pinned_conversation ON pinned_conversation.uuid = chat_history.uuid
+            WHERE chat_history.deleted_at IS NULL
+            GROUP BY chat_history.uuid, "user".role, pinned_conversation.uuid
+            ORDER BY chat_history.uuid ASC;
+            "#
+        );
+        Ok(sqlx::query_as(query).fetch_all(&self.pool).await?)
+    }
+
+    async fn purge_conversation(&self, uuid: &str, cutoff: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            DELETE FROM chat_history
+            WHERE uuid = $1
+                AND deleted_at IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM chat_history
+                    WHERE uuid = $1 AND deleted_at IS NULL AND time >= CAST($2 AS TIMESTAMP)
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM pinned_conversation WHERE uuid = $1
+                );
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(uuid)
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn set_pinned(&self, uuid: &str, pinned: bool) -> anyhow::Result<()> {
+        let query = if pinned {
+            indoc!(
+                "
+                INSERT INTO pinned_conversation (uuid)
+                VALUES ($1)
+                ON CONFLICT (uuid) DO NOTHING;
+                "
+            )
+        } else {
+            indoc!(
+                "
+                DELETE FROM pinned_conversation
+                WHERE uuid = $1;
+                "
+            )
+        };
+        sqlx::query(query).bind(uuid).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn record_purge_run(&self, source: &str, summary: &PurgeSummary) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO purge_run (source, conversations, messages, pinned_kept, trash_messages)
+            VALUES ($1, $2, $3, $4, $5);
+            "
+        );
+        sqlx::query(query)
+            .bind(source)
+            .bind(summary.conversations)
+            .bind(summary.messages)
+            .bind(summary.pinned_kept)
+            .bind(summary.trash_messages)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_purge_runs(&self, limit: u32) -> anyhow::Result<Vec<PurgeRun>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                to_char(time, 'YYYY-MM-DD HH24:MI:SS') AS time,
+                source,
+                conversations,
+                messages,
+                pinned_kept,
+                trash_messages
+            FROM purge_run
+            ORDER BY id DESC
+            LIMIT $1;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(i64::from(limit))
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn create_user(&self, uuid: &str, role: Role) -> anyhow::Result<()> {
+        let query = indoc!(
+            r#"
+            INSERT INTO "user" (uuid, role)
+            VALUES ($1, $2);
+            "#
+        );
+        sqlx::query(query)
+            .bind(uuid)
+            .bind(role.to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_user(&self, uuid: &str) -> anyhow::Result<Option<User>> {
+        let query = indoc!(
+            r#"
+            SELECT
+                uuid,
+                role,
+                to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                to_char(disabled_at, 'YYYY-MM-DD HH24:MI:SS') AS disabled_at
+            FROM "user"
+            WHERE uuid = $1;
+            "#
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn list_users(&self) -> anyhow::Result<Vec<User>> {
+        let query = indoc!(
+            r#"
+            SELECT
+                uuid,
+                role,
+                to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                to_char(disabled_at, 'YYYY-MM-DD HH24:MI:SS') AS disabled_at
+            FROM "user"
+            ORDER BY "user".created_at ASC;
+            "#
+        );
+        Ok(sqlx::query_as(query).fetch_all(&self.pool).await?)
+    }
+
+    async fn count_users_by_role(&self, role: Role) -> anyhow::Result<i64> {
+        let query = indoc!(
+            r#"
+            SELECT COUNT(*)
+            FROM "user"
+            WHERE role = $1;
+            "#
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(role.to_string())
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn set_user_disabled(&self, uuid: &str, disabled: bool) -> anyhow::Result<bool> {
+        let query = if disabled {
+            indoc!(
+                r#"
+                UPDATE "user"
+                SET disabled_at = COALESCE(disabled_at, (NOW() AT TIME ZONE 'UTC'))
+                WHERE uuid = $1;
+                "#
+            )
+        } else {
+            indoc!(
+                r#"
+                UPDATE "user"
+                SET disabled_at = NULL
+                WHERE uuid = $1;
+                "#
+            )
+        };
+        let result = sqlx::query(query).bind(uuid).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn create_api_key(
+        &self,
+        uuid: &str,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+    ) -> anyhow::Result<i64> {
+        let query = indoc!(
+            "
+            INSERT INTO api_key (uuid, name, key_hash, prefix)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id;
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(uuid)
+            .bind(name)
+            .bind(key_hash)
+            .bind(prefix)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn list_api_keys(&self, uuid: &str) -> anyhow::Result<Vec<ApiKey>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                name,
+                prefix,
+                to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                to_char(last_used_at, 'YYYY-MM-DD HH24:MI:SS') AS last_used_at,
+                last_used_ip
+            FROM api_key
+            WHERE uuid = $1 AND revoked_at IS NULL
+            ORDER BY id ASC;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> anyhow::Result<Option<ApiKey>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                name,
+                prefix,
+                to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') AS created_at,
+                to_char(last_used_at, 'YYYY-MM-DD HH24:MI:SS') AS last_used_at,
+                last_used_ip
+            FROM api_key
+            WHERE key_hash = $1 AND revoked_at IS NULL;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(key_hash)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn touch_api_key(&self, id: i64, ip: Option<String>) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            UPDATE api_key
+            SET last_used_at = NOW() AT TIME ZONE 'UTC', last_used_ip = $1
+            WHERE id = $2;
+            "
+        );
+        sqlx::query(query)
+            .bind(ip)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, uuid: &str, id: i64) -> anyhow::Result<bool> {
+        let query = indoc!(
+            "
+            UPDATE api_key
+            SET revoked_at = NOW() AT TIME ZONE 'UTC'
+            WHERE id = $1 AND uuid = $2 AND revoked_at IS NULL;
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(id)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn load_buckets(&self) -> anyhow::Result<HashMap<String, TokenBucket>> {
+        let query = indoc!(
+            "
+            SELECT
+                key,
+                tokens,
+                updated_at
+            FROM rate_limit_bucket;
+            "
+        );
+        let list: Vec<KeyedBucket> = sqlx::query_as(query).fetch_all(&self.pool).await?;
+        Ok(list.into_iter().map(|kb| (kb.key, kb.bucket)).collect())
+    }
+
+    async fn persist_bucket(&self, key: &str, bucket: &TokenBucket) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO rate_limit_bucket (key, tokens, updated_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE
+            SET tokens = excluded.tokens, updated_at = excluded.updated_at;
+            "
+        );
+        sqlx::query(query)
+            .bind(key)
+            .bind(bucket.tokens)
+            .bind(bucket.updated_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_idle_buckets(&self, threshold: f64) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            DELETE FROM rate_limit_bucket
+            WHERE updated_at < $1;
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(threshold)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn record_usage(
+        &self,
+        uuid: &str,
+        model: &str,
+        usage: &TokenUsage,
+        cost: f64,
+    ) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO token_usage (uuid, model, prompt_tokens, completion_tokens, cost)
+            VALUES ($1, $2, $3, $4, $5);
+            "
+        );
+        sqlx::query(query)
+            .bind(uuid)
+            .bind(model)
+            .bind(usage.prompt_tokens)
+            .bind(usage.completion_tokens)
+            .bind(cost)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn sum_usage_since(&self, uuid: &str, period: Period) -> anyhow::Result<TokenUsage> {
+        let field = match period {
+            Period::Day => "day",
+            Period::Month => "month",
+        };
+        let query = indoc!(
+            "
+            SELECT
+                COALESCE(SUM(prompt_tokens), 0)::BIGINT AS prompt_tokens,
+                COALESCE(SUM(completion_tokens), 0)::BIGINT AS completion_tokens
+            FROM token_usage
+            WHERE uuid = $1 AND time >= date_trunc($2, NOW() AT TIME ZONE 'UTC');
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .bind(field)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn daily_usage(&self, uuid: &str, days: u32) -> anyhow::Result<Vec<DailyUsage>> {
+        let query = indoc!(
+            "
+            SELECT
+                time::date::text AS day,
+                SUM(prompt_tokens)::BIGINT AS prompt_tokens,
+                SUM(completion_tokens)::BIGINT AS completion_tokens
+            FROM token_usage
+            WHERE uuid = $1
+                AND time >= date_trunc('day', NOW() AT TIME ZONE 'UTC') - make_interval(days => $2)
+            GROUP BY day
+            ORDER BY day ASC;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .bind(i32::try_from(days).unwrap_or(i32::MAX))
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn aggregate_cost(&self, group: CostGroup, days: u32) -> anyhow::Result<Vec<CostBucket>> {
+        let key = match group {
+            CostGroup::User => "uuid",
+            CostGroup::Model => "model",
+            CostGroup::Day => "time::date::text",
+        };
+        let query = formatdoc!(
+            "
+            SELECT
+                {key} AS key,
+                SUM(prompt_tokens)::BIGINT AS prompt_tokens,
+                SUM(completion_tokens)::BIGINT AS completion_tokens,
+                SUM(cost) AS cost
+            FROM token_usage
+            WHERE time >= date_trunc('day', NOW() AT TIME ZONE 'UTC') - make_interval(days => $1)
+            GROUP BY {key}
+            ORDER BY cost DESC;
+            "
+        );
+        Ok(sqlx::query_as(&query)
+            .bind(i32::try_from(days).unwrap_or(i32::MAX))
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn spent_today(&self, uuid: Option<&str>) -> anyhow::Result<f64> {
+        let query = indoc!(
+            "
+            SELECT COALESCE(SUM(cost), 0.0)
+            FROM token_usage
+            WHERE time >= date_trunc('day', NOW() AT TIME ZONE 'UTC')
+                AND ($1::TEXT IS NULL OR uuid = $1);
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(uuid)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+    use crate::store::test::exercise_repository;
+
+    /// Runs against the database in `AGENT_WEB_TEST_POSTGRES_URL`, e.g.
+    /// `postgres://postgres@127.0.0.1:5432/postgres`, with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs AGENT_WEB_TEST_POSTGRES_URL"]
+    async fn postgres_repository() {
+        let url = std::env::var("AGENT_WEB_TEST_POSTGRES_URL")
+            .expect("AGENT_WEB_TEST_POSTGRES_URL must point to a Postgres database");
+        let repo = PostgresRepository::connect(&url, 2).await.unwrap();
+        exercise_repository(&repo).await;
+    }
+}
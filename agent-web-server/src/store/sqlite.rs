@@ -0,0 +1,1054 @@
+/// SQLite backend, the default, a single `store.db` in data directory.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use indoc::{formatdoc, indoc};
+use sqlx::{
+    Sqlite, SqlitePool,
+    migrate::{Migrate, MigrateDatabase, Migrator},
+};
+
+use super::{KeyedBucket, MigrationInfo, Repository, StoredContent};
+use crate::{
+    agent::{ChatMessage, HistoryCursor, TokenUsage},
+    auth::{ApiKey, Role, User},
+    cost::{CostBucket, CostGroup},
+    indoc_info,
+    ratelimit::TokenBucket,
+    retention::{ConversationActivity, PurgeRun, PurgeSummary},
+    search::{DB_MARK_END, DB_MARK_START, SearchHit},
+    usage::{DailyUsage, Period},
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Quote every word of user input as an FTS5 string, so that operators,
+/// column filters and stray quotes match literally instead of failing the query.
+fn fts5_query(input: &str) -> String {
+    input
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug)]
+pub struct SqliteRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteRepository {
+    pub async fn open(db_path: &Path, max_conn: u32) -> anyhow::Result<Self> {
+        let db_url = format!("sqlite://{}", db_path.to_string_lossy());
+
+        if Sqlite::database_exists(&db_url).await? {
+            indoc_info!("Building DB connection pool from existing database...");
+        } else {
+            Sqlite::create_database(&db_url).await?;
+            indoc_info!("Building DB connection pool from newly created database...");
+        }
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(max_conn)
+            .connect(&db_url)
+            .await?;
+        indoc_info!("DB init complete.");
+        Ok(Self { pool })
+    }
+
+    pub fn with_pool(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Check that db_path is an intact database of this server, without modifying it.
+    pub async fn verify(db_path: &Path) -> anyhow::Result<()> {
+        let options = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(db_path)
+            .read_only(true);
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        let repo = Self::with_pool(pool);
+        let integrity: String = sqlx::query_scalar("PRAGMA integrity_check;")
+            .fetch_one(&repo.pool)
+            .await?;
+        if integrity != "ok" {
+            anyhow::bail!("integrity check failed: {integrity}");
+        }
+        if !repo.table_exists("chat_history").await? {
+            anyhow::bail!("no chat_history table");
+        }
+        repo.pool.close().await;
+        Ok(())
+    }
+
+    /// Databases created before versioned migrations have no migration table,
+    /// add the columns that the ad-hoc setup used to add on startup,
+    /// so that the baseline migration applies on top of them.
+    async fn upgrade_legacy_schema(&self) -> anyhow::Result<()> {
+        if self.table_exists("_sqlx_migrations").await?
+            || !self.table_exists("chat_history").await?
+        {
+            return Ok(());
+        }
+        indoc_info!("Upgrading database created before versioned migrations...");
+        self.add_column_if_missing(
+            "chat_history",
+            "prompt_tokens",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        self.add_column_if_missing(
+            "chat_history",
+            "completion_tokens",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        if self.table_exists("token_usage").await? {
+            self.add_column_if_missing("token_usage", "cost", "REAL NOT NULL DEFAULT 0")
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn table_exists(&self, table: &str) -> anyhow::Result<bool> {
+        let query = indoc!(
+            "
+            SELECT COUNT(*)
+            FROM sqlite_master
+            WHERE type = 'table' AND name = $1;
+            "
+        );
+        let count: i64 = sqlx::query_scalar(query)
+            .bind(table)
+            .fetch_one(&self.pool)
+            .await
+            .with_context(|| format!("inspect table {table}"))?;
+        Ok(count > 0)
+    }
+
+    async fn add_column_if_missing(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            SELECT COUNT(*)
+            FROM pragma_table_info($1)
+            WHERE name = $2;
+            "
+        );
+        let exists: i64 = sqlx::query_scalar(query)
+            .bind(table)
+            .bind(column)
+            .fetch_one(&self.pool)
+            .await
+            .with_context(|| format!("inspect table {table}"))?;
+        if exists > 0 {
+            return Ok(());
+        }
+        let query = format!("ALTER TABLE {table} ADD COLUMN {column} {definition};");
+        sqlx::query(&query)
+            .execute(&self.pool)
+            .await
+            .with_context(|| format!("add column {table}.{column}"))?;
+        indoc_info!("Added column {table}.{column}.");
+        Ok(())
+    }
+
+    async fn applied_versions(&self) -> anyhow::Result<Vec<i64>> {
+        let mut conn = self.pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        let mut versions: Vec<i64> = conn
+            .list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|m| m.version)
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.upgrade_legacy_schema().await?;
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .with_context(|| "run migrations")?;
+        Ok(())
+    }
+
+    async fn migration_status(&self) -> anyhow::Result<Vec<MigrationInfo>> {
+        let applied = self.applied_versions().await?;
+        Ok(MigrationInfo::list(&MIGRATOR, &applied))
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1;").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn migrate_down(&self, target: Option<i64>) -> anyhow::Result<i64> {
+        let applied = self.applied_versions().await?;
+        let target = target.unwrap_or_else(|| MigrationInfo::previous(&applied));
+        MIGRATOR
+            .undo(&self.pool, target)
+            .await
+            .with_context(|| "revert migrations")?;
+        Ok(target)
+    }
+
+    async fn snapshot(&self, dest: &Path) -> anyhow::Result<()> {
+        sqlx::query("VACUUM INTO $1;")
+            .bind(dest.to_string_lossy())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_history(&self, uuid: &str) -> anyhow::Result<Vec<ChatMessage>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status,
+                time
+            FROM chat_history
+            WHERE uuid = $1 AND deleted_at IS NULL
+            ORDER BY id ASC;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn load_history_page(
+        &self,
+        uuid: &str,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let (condition, id, newest_first) = cursor.condition();
+        let order = if newest_first { "DESC" } else { "ASC" };
+        let query = formatdoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status,
+                time
+            FROM chat_history
+            WHERE uuid = $1 AND deleted_at IS NULL AND {condition}
+            ORDER BY id {order}
+            LIMIT $3;
+            "
+        );
+        let mut page: Vec<ChatMessage> = sqlx::query_as(&query)
+            .bind(uuid)
+            .bind(id)
+            .bind(i64::from(limit))
+            .fetch_all(&self.pool)
+            .await?;
+        if newest_first {
+            page.reverse();
+        }
+        Ok(page)
+    }
+
+    async fn persist_message(&self, message: &ChatMessage) -> anyhow::Result<(i64, String)> {
+        let query = indoc!(
+            "
+            INSERT INTO chat_history (
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, time;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(&message.uuid)
+            .bind(&message.content)
+            .bind(&message.role)
+            .bind(message.usage.prompt_tokens)
+            .bind(message.usage.completion_tokens)
+            .bind(&message.model)
+            .bind(message.latency_ms)
+            .bind(&message.finish_reason)
+            .bind(&message.status)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn import_messages(&self, messages: &[ChatMessage]) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO chat_history (
+                uuid,
+                message,
+                role,
+                prompt_tokens,
+                completion_tokens,
+                model,
+                latency_ms,
+                finish_reason,
+                status,
+                time
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10);
+            "
+        );
+        let mut tx = self.pool.begin().await?;
+        for message in messages {
+            sqlx::query(query)
+                .bind(&message.uuid)
+                .bind(&message.content)
+                .bind(&message.role)
+                .bind(message.usage.prompt_tokens)
+                .bind(message.usage.completion_tokens)
+                .bind(&message.model)
+                .bind(message.latency_ms)
+                .bind(&message.finish_reason)
+                .bind(&message.status)
+                .bind(&message.time)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_conversations(&self) -> anyhow::Result<Vec<String>> {
+        let query = indoc!(
+            "
+            SELECT DISTINCT uuid
+            FROM chat_history
+            WHERE deleted_at IS NULL
+            ORDER BY uuid ASC;
+            "
+        );
+        Ok(sqlx::query_scalar(query).fetch_all(&self.pool).await?)
+    }
+
+    async fn scan_contents(&self, after_id: i64, limit: u32) -> anyhow::Result<Vec<StoredContent>> {
+        let query = indoc!(
+            "
+            SELECT id, uuid, message
+            FROM chat_history
+            WHERE id > $1
+            ORDER BY id ASC
+            LIMIT $2;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn update_contents(&self, contents: &[StoredContent]) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            UPDATE chat_history
+            SET message = $1
+            WHERE id = $2;
+            "
+        );
+        let mut tx = self.pool.begin().await?;
+        for content in contents {
+            sqlx::query(query)
+                .bind(&content.message)
+                .bind(content.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn any_content_with_prefix(&self, prefix: &str) -> anyhow::Result<bool> {
+        let table = indoc!(
+            "
+            SELECT EXISTS(
+                SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'chat_history'
+            );
+            "
+        );
+        if !sqlx::query_scalar::<_, bool>(table)
+            .fetch_one(&self.pool)
+            .await?
+        {
+            return Ok(false);
+        }
+        // LIKE would be case-insensitive and take wildcards
+        let query = indoc!(
+            "
+            SELECT EXISTS(
+                SELECT 1 FROM chat_history WHERE substr(message, 1, length($1)) = $1
+            );
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(prefix)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn clear_history(&self, uuid: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            UPDATE chat_history
+            SET deleted_at = CURRENT_TIMESTAMP
+            WHERE uuid = $1 AND deleted_at IS NULL;
+            "
+        );
+        let r = sqlx::query(query).bind(uuid).execute(&self.pool).await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn restore_history(&self, uuid: &str, since: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            UPDATE chat_history
+            SET deleted_at = NULL
+            WHERE uuid = $1 AND deleted_at >= $2;
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(uuid)
+            .bind(since)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn count_trash(&self, before: &str) -> anyhow::Result<i64> {
+        let query = indoc!(
+            "
+            SELECT COUNT(*)
+            FROM chat_history
+            WHERE deleted_at < $1;
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(before)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn purge_trash(&self, before: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            DELETE FROM chat_history
+            WHERE deleted_at < $1;
+            "
+        );
+        let r = sqlx::query(query).bind(before).execute(&self.pool).await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn search_history(
+        &self,
+        uuid: &str,
+        query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        let query_sql = formatdoc!(
+            "
+            SELECT
+                chat_history.id AS id,
+                chat_history.role AS role,
+                snippet(chat_history_fts, 0, '{DB_MARK_START}', '{DB_MARK_END}', '…', 16) AS snippet,
+                chat_history.time AS time
+            FROM chat_history_fts
+            JOIN chat_history ON chat_history.id = chat_history_fts.rowid
+            WHERE chat_history_fts MATCH $1
+                AND chat_history.uuid = $2
+                AND chat_history.deleted_at IS NULL
+            ORDER BY chat_history_fts.rank, chat_history.id DESC
+            LIMIT $3 OFFSET $4;
+            "
+        );
+        Ok(sqlx::query_as(&query_sql)
+            .bind(fts5_query(query))
+            .bind(uuid)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(SearchHit::escape_db_snippet)
+            .collect())
+    }
+
+    async fn conversation_activity(&self) -> anyhow::Result<Vec<ConversationActivity>> {
+        let query = indoc!(
+            "
+            SELECT
+                chat_history.uuid AS uuid,
+                user.role AS role,
+                MAX(chat_history.time) AS last_active,
+                COUNT(*) AS messages,
+                pinned_conversation.uuid IS NOT NULL AS pinned
+            FROM chat_history
+            LEFT JOIN user ON user.uuid = chat_history.uuid
+            LEFT JOIN pinned_conversation ON pinned_conversation.uuid = chat_history.uuid
+            WHERE chat_history.deleted_at IS NULL
+            GROUP BY chat_history.uuid
+            ORDER BY chat_history.uuid ASC;
+            "
+        );
+        Ok(sqlx::query_as(query).fetch_all(&self.pool).await?)
+    }
+
+    async fn purge_conversation(&self, uuid: &str, cutoff: &str) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            DELETE FROM chat_history
+            WHERE uuid = $1
+                AND deleted_at IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM chat_history
+                    WHERE uuid = $1 AND deleted_at IS NULL AND time >= $2
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM pinned_conversation WHERE uuid = $1
+                );
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(uuid)
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn set_pinned(&self, uuid: &str, pinned: bool) -> anyhow::Result<()> {
+        let query = if pinned {
+            indoc!(
+                "
+                INSERT INTO pinned_conversation (uuid)
+                VALUES ($1)
+                ON CONFLICT (uuid) DO NOTHING;
+                "
+            )
+        } else {
+            indoc!(
+                "
+                DELETE FROM pinned_conversation
+                WHERE uuid = $1;
+                "
+            )
+        };
+        sqlx::query(query).bind(uuid).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn record_purge_run(&self, source: &str, summary: &PurgeSummary) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO purge_run (source, conversations, messages, pinned_kept, trash_messages)
+            VALUES ($1, $2, $3, $4, $5);
+            "
+        );
+        sqlx::query(query)
+            .bind(source)
+            .bind(summary.conversations)
+            .bind(summary.messages)
+            .bind(summary.pinned_kept)
+            .bind(summary.trash_messages)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_purge_runs(&self, limit: u32) -> anyhow::Result<Vec<PurgeRun>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                time,
+                source,
+                conversations,
+                messages,
+                pinned_kept,
+                trash_messages
+            FROM purge_run
+            ORDER BY id DESC
+            LIMIT $1;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn create_user(&self, uuid: &str, role: Role) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO user (uuid, role)
+            VALUES ($1, $2);
+            "
+        );
+        sqlx::query(query)
+            .bind(uuid)
+            .bind(role.to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_user(&self, uuid: &str) -> anyhow::Result<Option<User>> {
+        let query = indoc!(
+            "
+            SELECT
+                uuid,
+                role,
+                created_at,
+                disabled_at
+            FROM user
+            WHERE uuid = $1;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn list_users(&self) -> anyhow::Result<Vec<User>> {
+        let query = indoc!(
+            "
+            SELECT
+                uuid,
+                role,
+                created_at,
+                disabled_at
+            FROM user
+            ORDER BY created_at ASC;
+            "
+        );
+        Ok(sqlx::query_as(query).fetch_all(&self.pool).await?)
+    }
+
+    async fn count_users_by_role(&self, role: Role) -> anyhow::Result<i64> {
+        let query = indoc!(
+            "
+            SELECT COUNT(*)
+            FROM user
+            WHERE role = $1;
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(role.to_string())
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn set_user_disabled(&self, uuid: &str, disabled: bool) -> anyhow::Result<bool> {
+        let query = if disabled {
+            indoc!(
+                "
+                UPDATE user
+                SET disabled_at = COALESCE(disabled_at, CURRENT_TIMESTAMP)
+                WHERE uuid = $1;
+                "
+            )
+        } else {
+            indoc!(
+                "
+                UPDATE user
+                SET disabled_at = NULL
+                WHERE uuid = $1;
+                "
+            )
+        };
+        let result = sqlx::query(query).bind(uuid).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn create_api_key(
+        &self,
+        uuid: &str,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+    ) -> anyhow::Result<i64> {
+        let query = indoc!(
+            "
+            INSERT INTO api_key (uuid, name, key_hash, prefix)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id;
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(uuid)
+            .bind(name)
+            .bind(key_hash)
+            .bind(prefix)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn list_api_keys(&self, uuid: &str) -> anyhow::Result<Vec<ApiKey>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                name,
+                prefix,
+                created_at,
+                last_used_at,
+                last_used_ip
+            FROM api_key
+            WHERE uuid = $1 AND revoked_at IS NULL
+            ORDER BY id ASC;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> anyhow::Result<Option<ApiKey>> {
+        let query = indoc!(
+            "
+            SELECT
+                id,
+                uuid,
+                name,
+                prefix,
+                created_at,
+                last_used_at,
+                last_used_ip
+            FROM api_key
+            WHERE key_hash = $1 AND revoked_at IS NULL;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(key_hash)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn touch_api_key(&self, id: i64, ip: Option<String>) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            UPDATE api_key
+            SET last_used_at = CURRENT_TIMESTAMP, last_used_ip = $1
+            WHERE id = $2;
+            "
+        );
+        sqlx::query(query)
+            .bind(ip)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, uuid: &str, id: i64) -> anyhow::Result<bool> {
+        let query = indoc!(
+            "
+            UPDATE api_key
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND uuid = $2 AND revoked_at IS NULL;
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(id)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn load_buckets(&self) -> anyhow::Result<HashMap<String, TokenBucket>> {
+        let query = indoc!(
+            "
+            SELECT
+                key,
+                tokens,
+                updated_at
+            FROM rate_limit_bucket;
+            "
+        );
+        let list: Vec<KeyedBucket> = sqlx::query_as(query).fetch_all(&self.pool).await?;
+        Ok(list.into_iter().map(|kb| (kb.key, kb.bucket)).collect())
+    }
+
+    async fn persist_bucket(&self, key: &str, bucket: &TokenBucket) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO rate_limit_bucket (key, tokens, updated_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE
+            SET tokens = excluded.tokens, updated_at = excluded.updated_at;
+            "
+        );
+        sqlx::query(query)
+            .bind(key)
+            .bind(bucket.tokens)
+            .bind(bucket.updated_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_idle_buckets(&self, threshold: f64) -> anyhow::Result<u64> {
+        let query = indoc!(
+            "
+            DELETE FROM rate_limit_bucket
+            WHERE updated_at < $1;
+            "
+        );
+        let r = sqlx::query(query)
+            .bind(threshold)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn record_usage(
+        &self,
+        uuid: &str,
+        model: &str,
+        usage: &TokenUsage,
+        cost: f64,
+    ) -> anyhow::Result<()> {
+        let query = indoc!(
+            "
+            INSERT INTO token_usage (uuid, model, prompt_tokens, completion_tokens, cost)
+            VALUES ($1, $2, $3, $4, $5);
+            "
+        );
+        sqlx::query(query)
+            .bind(uuid)
+            .bind(model)
+            .bind(usage.prompt_tokens)
+            .bind(usage.completion_tokens)
+            .bind(cost)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn sum_usage_since(&self, uuid: &str, period: Period) -> anyhow::Result<TokenUsage> {
+        let modifier = match period {
+            Period::Day => "start of day",
+            Period::Month => "start of month",
+        };
+        let query = indoc!(
+            "
+            SELECT
+                COALESCE(SUM(prompt_tokens), 0) AS prompt_tokens,
+                COALESCE(SUM(completion_tokens), 0) AS completion_tokens
+            FROM token_usage
+            WHERE uuid = $1 AND time >= datetime('now', $2);
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .bind(modifier)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn daily_usage(&self, uuid: &str, days: u32) -> anyhow::Result<Vec<DailyUsage>> {
+        let query = indoc!(
+            "
+            SELECT
+                date(time) AS day,
+                SUM(prompt_tokens) AS prompt_tokens,
+                SUM(completion_tokens) AS completion_tokens
+            FROM token_usage
+            WHERE uuid = $1 AND time >= datetime('now', 'start of day', $2)
+            GROUP BY day
+            ORDER BY day ASC;
+            "
+        );
+        Ok(sqlx::query_as(query)
+            .bind(uuid)
+            .bind(format!("-{days} days"))
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn aggregate_cost(&self, group: CostGroup, days: u32) -> anyhow::Result<Vec<CostBucket>> {
+        let key = match group {
+            CostGroup::User => "uuid",
+            CostGroup::Model => "model",
+            CostGroup::Day => "date(time)",
+        };
+        let query = formatdoc!(
+            "
+            SELECT
+                {key} AS key,
+                SUM(prompt_tokens) AS prompt_tokens,
+                SUM(completion_tokens) AS completion_tokens,
+                SUM(cost) AS cost
+            FROM token_usage
+            WHERE time >= datetime('now', 'start of day', $1)
+            GROUP BY {key}
+            ORDER BY cost DESC;
+            "
+        );
+        Ok(sqlx::query_as(&query)
+            .bind(format!("-{days} days"))
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn spent_today(&self, uuid: Option<&str>) -> anyhow::Result<f64> {
+        let query = indoc!(
+            "
+            SELECT COALESCE(SUM(cost), 0.0)
+            FROM token_usage
+            WHERE time >= datetime('now', 'start of day')
+                AND ($1 IS NULL OR uuid = $1);
+            "
+        );
+        Ok(sqlx::query_scalar(query)
+            .bind(uuid)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+}
+
+#[allow(unused)]
+pub(crate) mod test {
+    use super::*;
+    use crate::store::test::{exercise_repository, memory_repository};
+
+    /// Schema created by releases before versioned migrations.
+    const LEGACY_SCHEMA: &str = indoc!(
+        "
+        CREATE TABLE IF NOT EXISTS chat_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            uuid TEXT NOT NULL,
+            message TEXT,
+            role TEXT NOT NULL,
+            time DATETIME DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS idx_user_created_at ON chat_history (uuid, time);
+        "
+    );
+
+    /// Unmigrated database of a release before versioned migrations, holding one message of uuid.
+    pub async fn legacy_repository(uuid: &str) -> SqliteRepository {
+        let repo = memory_repository().await;
+        sqlx::query(LEGACY_SCHEMA)
+            .execute(&repo.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO chat_history (uuid, message, role) VALUES (?, 'hi', 'User');")
+            .bind(uuid)
+            .execute(&repo.pool)
+            .await
+            .unwrap();
+        repo
+    }
+
+    #[tokio::test]
+    async fn sqlite_repository() {
+        let repo = memory_repository().await;
+        exercise_repository(&repo).await;
+    }
+
+    #[test]
+    fn quote_fts5_query() {
+        assert_eq!(fts5_query(" regex  match "), r#""regex" "match""#);
+        assert_eq!(fts5_query(r#"say "hi"#), r#""say" """hi""#);
+    }
+
+    /// FTS5 syntax in the query must not widen the search beyond the caller.
+    #[tokio::test]
+    async fn search_syntax_does_not_leak() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        let secret = ChatMessage::create_user("victim", "secret token");
+        repo.persist_message(&secret).await.unwrap();
+        for query in [
+            "secret",
+            "secret OR token",
+            "message:secret",
+            "NEAR(secret token)",
+            "secr*",
+            r#"" OR "secret"#,
+            "*",
+        ] {
+            let hits = repo.search_history("attacker", query, 0, 10).await;
+            assert!(hits.unwrap().is_empty(), "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_legacy_database() {
+        let repo = legacy_repository("u").await;
+        repo.migrate().await.unwrap();
+        // idempotent on restart
+        repo.migrate().await.unwrap();
+
+        let history = repo.load_history("u").await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "hi");
+        assert_eq!(history[0].usage, TokenUsage::default());
+        let hits = repo.search_history("u", "hi", 0, 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        // its session is known as a user
+        let user = repo.find_user("u").await.unwrap().unwrap();
+        assert_eq!(user.get_role(), Role::User);
+        for table in ["api_key", "user", "rate_limit_bucket", "token_usage"] {
+            assert!(repo.table_exists(table).await.unwrap(), "{table}");
+        }
+        let status = repo.migration_status().await.unwrap();
+        assert!(status.iter().all(|m| m.applied));
+    }
+
+    #[tokio::test]
+    async fn migrate_down_and_up() {
+        let repo = memory_repository().await;
+        repo.migrate().await.unwrap();
+        repo.migrate_down(Some(0)).await.unwrap();
+        assert!(!repo.table_exists("chat_history").await.unwrap());
+        assert!(repo.applied_versions().await.unwrap().is_empty());
+        repo.migrate().await.unwrap();
+        assert!(repo.table_exists("chat_history").await.unwrap());
+    }
+}
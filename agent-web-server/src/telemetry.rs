@@ -0,0 +1,372 @@
+/// Distributed tracing: a span per request with child spans for database and upstream calls,
+/// exported over OTLP when `telemetry.otlp_endpoint` is set.
+///
+/// Users and conversations are only identified by a hash of their uuid.
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracer, SdkTracerProvider},
+};
+use sha2::{Digest, Sha256};
+use tracing::{Instrument, Span, field::Empty, info_span};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use uuid::Uuid;
+
+use crate::{agent::TokenUsage, config::TelemetryConfig};
+
+/// Taken from the client if given, echoed in the response.
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Longer client request ids are replaced by a generated one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Provider batching spans to the collector, `None` if export is off.
+/// Shut it down before exit to flush the last batch.
+pub fn provider(config: &TelemetryConfig) -> anyhow::Result<Option<SdkTracerProvider>> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    let resource = Resource::builder()
+        .with_service_name(config.service_name.clone())
+        .build();
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    Ok(Some(provider))
+}
+
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, SdkTracer>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+}
+
+/// Stable, not reversible id of a user or conversation for spans.
+pub fn hashed(uuid: &str) -> String {
+    let mut hash = format!("{:x}", Sha256::digest(uuid.as_bytes()));
+    hash.truncate(16);
+    hash
+}
+
+/// Tag the request span with the authenticated user.
+pub fn record_user(uuid: &str) {
+    Span::current().record("user", hashed(uuid));
+}
+
+/// Tag the request span with the conversation it works on.
+pub fn record_conversation(uuid: &str) {
+    Span::current().record("conversation_id", hashed(uuid));
+}
+
+/// Child span of a repository call, only within a request so that background jobs
+/// don't flood the collector.
+pub fn db_span(op: &str) -> Span {
+    if Span::current().is_none() {
+        return Span::none();
+    }
+    info_span!(
+        "db",
+        otel.name = format!("db {op}"),
+        otel.kind = "client",
+        db.operation.name = op,
+        otel.status_code = Empty,
+    )
+}
+
+/// Span of an upstream completion, tokens recorded once answered.
+pub fn llm_span(model: &str) -> Span {
+    info_span!(
+        "llm",
+        otel.name = format!("chat {model}"),
+        otel.kind = "client",
+        gen_ai.operation.name = "chat",
+        gen_ai.request.model = model,
+        gen_ai.usage.input_tokens = Empty,
+        gen_ai.usage.output_tokens = Empty,
+        otel.status_code = Empty,
+    )
+}
+
+pub fn record_tokens(span: &Span, usage: &TokenUsage) {
+    span.record("gen_ai.usage.input_tokens", usage.prompt_tokens);
+    span.record("gen_ai.usage.output_tokens", usage.completion_tokens);
+}
+
+/// Mark `span` failed if `res` is an error.
+pub fn record_outcome<T, E>(span: &Span, res: &Result<T, E>) {
+    if res.is_err() {
+        span.record("otel.status_code", "error");
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Middleware running every request in its own span, continuing the trace of
+/// a W3C `traceparent` header if the client sent one.
+pub async fn trace(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |p| p.as_str())
+        .to_string();
+    let method = req.method().clone();
+    let request_id = request_id(req.headers());
+    let span = info_span!(
+        "request",
+        otel.name = format!("{method} {route}"),
+        otel.kind = "server",
+        http.request.method = %method,
+        http.route = route,
+        request_id = request_id,
+        user = Empty,
+        conversation_id = Empty,
+        http.response.status_code = Empty,
+        otel.status_code = Empty,
+    );
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    span.set_parent(parent);
+
+    let mut response = next.run(req).instrument(span.clone()).await;
+    let status = response.status();
+    // an OTLP integer, unsigned values are exported as strings
+    span.record("http.response.status_code", i64::from(status.as_u16()));
+    if status.is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+    // valid, it was either a header value or a uuid
+    if let Ok(id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID, id);
+    }
+    response
+}
+
+#[allow(unused)]
+mod test {
+    use std::{collections::HashMap, sync::mpsc, thread};
+
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::Path,
+        middleware,
+        routing::{get, post},
+    };
+    use tokio::net::TcpListener;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{
+        listen::serve_on,
+        store::{Repository, metered::MeteredRepository, test::memory_repository},
+    };
+
+    // opentelemetry-proto and prost are dev-dependencies only
+    #[cfg(test)]
+    type Exports =
+        mpsc::Receiver<opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest>;
+
+    /// OTLP/HTTP collector on its own runtime, as the exporter blocks its thread while posting.
+    #[cfg(test)]
+    fn collector() -> (String, Exports) {
+        use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+        use prost::Message;
+
+        let (export_tx, export_rx) = mpsc::channel();
+        let (addr_tx, addr_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let app = Router::new().route(
+                    "/v1/traces",
+                    post(move |body: Bytes| async move {
+                        let export = ExportTraceServiceRequest::decode(body).unwrap();
+                        export_tx.send(export).unwrap();
+                    }),
+                );
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                addr_tx.send(listener.local_addr().unwrap()).unwrap();
+                axum::serve(listener, app).await.unwrap();
+            });
+        });
+        (format!("http://{}", addr_rx.recv().unwrap()), export_rx)
+    }
+
+    #[cfg(test)]
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[cfg(test)]
+    #[tokio::test]
+    async fn spans_reach_collector() {
+        use opentelemetry_proto::tonic::{
+            common::v1::any_value::Value,
+            trace::v1::{Span as ProtoSpan, span::SpanKind},
+        };
+
+        let (endpoint, exports) = collector();
+        let config = TelemetryConfig {
+            otlp_endpoint: Some(endpoint),
+            service_name: "telemetry-test".into(),
+        };
+        let provider = provider(&config).unwrap().unwrap();
+        // global, a scoped subscriber misses spans the SQLite worker thread enters
+        tracing::subscriber::set_global_default(
+            tracing_subscriber::registry().with(layer(&provider)),
+        )
+        .unwrap();
+
+        let repo = memory_repository().await;
+        let repo: &'static MeteredRepository =
+            Box::leak(Box::new(MeteredRepository::new(Box::new(repo))));
+        // outside of a request, not traced
+        repo.migrate().await.unwrap();
+        let app = Router::new()
+            .route(
+                "/conversations/{uuid}",
+                post(move |Path(uuid): Path<String>| async move {
+                    record_user("alice");
+                    record_conversation(&uuid);
+                    repo.ping().await.unwrap();
+                    let span = llm_span("test-model");
+                    async {}.instrument(span.clone()).await;
+                    let usage = TokenUsage {
+                        prompt_tokens: 3,
+                        completion_tokens: 5,
+                    };
+                    record_tokens(&span, &usage);
+                }),
+            )
+            .layer(middleware::from_fn(trace));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener, app));
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("http://{addr}/conversations/c1"))
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .header(REQUEST_ID, "req-42")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID], "req-42");
+        let response = client
+            .post(format!("http://{addr}/conversations/c2"))
+            .send()
+            .await
+            .unwrap();
+        let generated = response.headers()[REQUEST_ID].to_str().unwrap().to_string();
+        assert!(Uuid::parse_str(&generated).is_ok());
+
+        provider.force_flush().unwrap();
+        let mut services = Vec::new();
+        let mut spans: Vec<ProtoSpan> = Vec::new();
+        for export in exports.try_iter() {
+            for resource_spans in export.resource_spans {
+                let resource = resource_spans.resource.unwrap();
+                services.extend(
+                    resource
+                        .attributes
+                        .into_iter()
+                        .filter(|kv| kv.key == "service.name")
+                        .filter_map(|kv| kv.value?.value),
+                );
+                for scope_spans in resource_spans.scope_spans {
+                    spans.extend(scope_spans.spans);
+                }
+            }
+        }
+        let string = |s: &str| Value::StringValue(s.into());
+        assert!(services.contains(&string("telemetry-test")));
+        let attrs = |span: &ProtoSpan| -> HashMap<String, Value> {
+            span.attributes
+                .iter()
+                .filter_map(|kv| Some((kv.key.clone(), kv.value.clone()?.value?)))
+                .collect()
+        };
+        let request_with_id = |id: &str| {
+            spans
+                .iter()
+                .find(|s| attrs(s).get("request_id") == Some(&string(id)))
+                .unwrap()
+        };
+        let children = |parent: &ProtoSpan| -> Vec<&ProtoSpan> {
+            spans
+                .iter()
+                .filter(|s| s.parent_span_id == parent.span_id && s.trace_id == parent.trace_id)
+                .collect()
+        };
+
+        let request = request_with_id("req-42");
+        assert_eq!(request.name, "POST /conversations/{uuid}");
+        assert_eq!(request.kind, SpanKind::Server as i32);
+        // continues the trace of the caller
+        assert_eq!(hex(&request.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&request.parent_span_id), "00f067aa0ba902b7");
+        let request_attrs = attrs(request);
+        assert_eq!(request_attrs["http.route"], string("/conversations/{uuid}"));
+        assert_eq!(
+            request_attrs["http.response.status_code"],
+            Value::IntValue(200)
+        );
+        assert_eq!(request_attrs["user"], string(&hashed("alice")));
+        assert_eq!(request_attrs["conversation_id"], string(&hashed("c1")));
+        assert!(!request_attrs.values().any(|v| *v == string("alice")));
+
+        let children = children(request);
+        assert_eq!(children.len(), 2);
+        let db = children.iter().find(|s| s.name == "db ping").unwrap();
+        assert_eq!(attrs(db)["db.operation.name"], string("ping"));
+        let llm = children
+            .iter()
+            .find(|s| s.name == "chat test-model")
+            .unwrap();
+        assert_eq!(llm.kind, SpanKind::Client as i32);
+        assert_eq!(attrs(llm)["gen_ai.usage.output_tokens"], Value::IntValue(5));
+
+        // a new trace without traceparent
+        let other = request_with_id(&generated);
+        assert!(other.parent_span_id.is_empty());
+        assert_ne!(other.trace_id, request.trace_id);
+        assert!(!spans.iter().any(|s| s.name == "db migrate"));
+    }
+}
@@ -7,12 +7,36 @@
 /// - Debug: *  
 use std::{env, fs::create_dir_all};
 
-use time::macros::{format_description, offset};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use time::{
+    UtcOffset,
+    format_description::{BorrowedFormatItem, well_known::Rfc3339},
+    macros::format_description,
+};
 use tracing::level_filters::LevelFilter;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
 use tracing_subscriber::{
-    Layer, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt,
+    Layer, Registry,
+    fmt::{MakeWriter, format::FmtSpan, time::OffsetTime},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
 };
 
+use crate::{
+    config::{LogFormat, LogRotation, ServerConfig},
+    states::DATA_DIR,
+    telemetry,
+};
+
+const READABLE_TIME: &[BorrowedFormatItem] =
+    format_description!("[year]/[month]/[day]-[hour]:[minute]:[second]");
+/// E.g. `+08:00` or `-05:30`.
+const OFFSET_FORMAT: &[BorrowedFormatItem] =
+    format_description!("[offset_hour sign:mandatory]:[offset_minute]");
+
 /// Indoc format flavor of tracing error, abort!
 #[macro_export]
 macro_rules! indoc_error {
@@ -50,48 +74,217 @@ macro_rules! indoc_debug {
     }};
 }
 
-pub fn init_tracing(debug: bool) -> tracing_appender::non_blocking::WorkerGuard {
+/// Flushes the log file and exported spans when dropped, keep it until exit.
+pub struct TracingGuard {
+    _file: WorkerGuard,
+    spans: Option<SdkTracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.spans.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush spans: {e}");
+        }
+    }
+}
+
+/// Offset of [crate::config::LogConfig::timezone], `local` is read from the system.
+/// Resolve `local` before other threads start, the system's offset can't be read safely after.
+pub fn utc_offset(timezone: &str) -> anyhow::Result<UtcOffset> {
+    match timezone {
+        "UTC" | "utc" => Ok(UtcOffset::UTC),
+        "local" => Ok(UtcOffset::current_local_offset()?),
+        offset => Ok(UtcOffset::parse(offset, OFFSET_FORMAT)?),
+    }
+}
+
+fn fmt_layer<W>(
+    format: LogFormat,
+    offset: UtcOffset,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    // spans are for the collector, events within them show their fields
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_span_events(FmtSpan::NONE)
+        .with_target(false);
+    let readable = OffsetTime::new(offset, READABLE_TIME);
+    match format {
+        LogFormat::Pretty => layer.pretty().with_timer(readable).boxed(),
+        LogFormat::Compact => layer.compact().with_timer(readable).boxed(),
+        // machine readable time, and the innermost span only, e.g. the request with its id
+        LogFormat::Json => layer
+            .json()
+            .with_timer(OffsetTime::new(offset, Rfc3339))
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+pub fn init_tracing(debug: bool, config: &ServerConfig) -> TracingGuard {
     let filter = if debug {
         LevelFilter::DEBUG
     } else {
         LevelFilter::INFO
     };
+    let log = &config.log;
+    // first, while the process has a single thread
+    let (offset, offset_error) = match utc_offset(&log.timezone) {
+        Ok(offset) => (offset, None),
+        Err(e) => (UtcOffset::UTC, Some(e)),
+    };
 
-    let exec_path = env::current_exe().expect("cannot get exec path");
-    let exec_path = exec_path
-        .canonicalize()
-        .expect("convert exec path to absolute path");
-    let exec_dir = exec_path.parent().expect("exec has no parent");
-    let log_dir = exec_dir.join("server_log");
+    let log_dir = match &log.dir {
+        Some(dir) => DATA_DIR.get().unwrap().join(dir),
+        None => {
+            let exec_path = env::current_exe().expect("cannot get exec path");
+            let exec_path = exec_path
+                .canonicalize()
+                .expect("convert exec path to absolute path");
+            let exec_dir = exec_path.parent().expect("exec has no parent");
+            exec_dir.join("server_log")
+        }
+    };
     create_dir_all(&log_dir).expect("create log dir");
-    let offset = offset!(+8);
-    let formatter = format_description!("[year]/[month]/[day]-[hour]:[minute]:[second]");
-    let time = tracing_subscriber::fmt::time::OffsetTime::new(offset, formatter);
-    let file_appender = tracing_appender::rolling::daily(log_dir, "");
+    let rotation = match log.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut file_appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&log.file_prefix);
+    if log.max_files > 0 {
+        file_appender = file_appender.max_log_files(log.max_files);
+    }
+    let file_appender = file_appender.build(&log_dir).expect("create log file");
     let (non_block_file_wt, guard) = tracing_appender::non_blocking(file_appender);
-    let file_layer = tracing_subscriber::fmt::layer()
-        .pretty()
-        .with_timer(time.clone())
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_span_events(FmtSpan::ACTIVE)
-        .with_writer(non_block_file_wt)
-        .with_ansi(false)
-        .with_target(false)
-        .with_filter(LevelFilter::DEBUG);
-    let std_layer = tracing_subscriber::fmt::layer()
-        .pretty()
-        .with_timer(time)
-        .with_file(true)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_span_events(FmtSpan::ACTIVE)
-        .with_target(false)
-        .with_filter(filter);
-
-    tracing_subscriber::registry()
-        .with(file_layer)
-        .with(std_layer)
-        .init();
-    guard
+    let file_layer = fmt_layer(log.file_format, offset, non_block_file_wt, false)
+        .with_filter(LevelFilter::DEBUG)
+        .boxed();
+    let std_layer = fmt_layer(log.format, offset, std::io::stdout, true)
+        .with_filter(filter)
+        .boxed();
+    let mut layers = vec![file_layer, std_layer];
+
+    let (spans, export_error) = match telemetry::provider(&config.telemetry) {
+        Ok(provider) => (provider, None),
+        Err(e) => (None, Some(e)),
+    };
+    if let Some(provider) = &spans {
+        layers.push(
+            telemetry::layer(provider)
+                .with_filter(LevelFilter::INFO)
+                .boxed(),
+        );
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    if let Some(e) = offset_error {
+        indoc_warn!(
+            "Timezone `{}` not resolved, logging in UTC, error: {e:#}",
+            log.timezone
+        );
+    }
+    if let Some(e) = export_error {
+        indoc_warn!("OTLP exporter failed to start, spans are not exported: {e:#}");
+    }
+    TracingGuard {
+        _file: guard,
+        spans,
+    }
+}
+
+#[allow(unused)]
+mod test {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Captured;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn timezones() {
+        let hours = |h| UtcOffset::from_hms(h, 0, 0).unwrap();
+        assert_eq!(utc_offset("+08:00").unwrap(), hours(8));
+        assert_eq!(
+            utc_offset("-05:30").unwrap(),
+            UtcOffset::from_hms(-5, -30, 0).unwrap()
+        );
+        assert_eq!(utc_offset("UTC").unwrap(), UtcOffset::UTC);
+        for invalid in ["08:00", "+8", "Asia/Shanghai", ""] {
+            assert!(utc_offset(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let captured = Captured::default();
+        let offset = utc_offset("+08:00").unwrap();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(
+            LogFormat::Json,
+            offset,
+            captured.clone(),
+            false,
+        ));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", request_id = "req-7");
+            let _entered = span.enter();
+            crate::indoc_info!(
+                "
+                Purged {} conversations,
+                {} kept.
+                ",
+                3,
+                1
+            );
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 1, "{output}");
+        let event: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(
+            event["fields"]["message"],
+            "Purged 3 conversations,\n1 kept.\n"
+        );
+        assert_eq!(event["span"]["request_id"], "req-7");
+        let timestamp = event["timestamp"].as_str().unwrap();
+        assert!(timestamp.ends_with("+08:00"), "{timestamp}");
+    }
 }
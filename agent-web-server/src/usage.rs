@@ -0,0 +1,96 @@
+/// Token usage accounting and per user quotas.
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+
+use crate::{
+    agent::TokenUsage,
+    auth::{JwtClaim, Role},
+    config::{Quota, QuotaConfig},
+    states::server_config,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+/// Tokens consumed by a user in one UTC day.
+#[derive(FromRow, Debug, Serialize)]
+pub struct DailyUsage {
+    pub day: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub today: i64,
+    pub this_month: i64,
+    pub quota: Quota,
+    pub daily: Vec<DailyUsage>,
+}
+
+/// User quota overrides role quota, no quota at all means unlimited.
+pub fn resolve_quota(config: &QuotaConfig, uuid: &str, role: Role) -> Quota {
+    config
+        .users
+        .get(uuid)
+        .or_else(|| config.roles.get(&role.to_string()))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Reject the caller once any of its quotas is used up.
+pub async fn check_quota(claim: &JwtClaim) -> Result<(), String> {
+    let config = server_config();
+    let quota = resolve_quota(&config.quota, &claim.uuid, claim.role);
+    if let Some(limit) = quota.daily_tokens
+        && total_tokens(&claim.uuid, Period::Day).await >= limit
+    {
+        return Err("Daily token quota exceeded, resets at 00:00 UTC.".into());
+    }
+    if let Some(limit) = quota.monthly_tokens
+        && total_tokens(&claim.uuid, Period::Month).await >= limit
+    {
+        return Err("Monthly token quota exceeded.".into());
+    }
+    Ok(())
+}
+
+pub async fn report(claim: &JwtClaim, days: u32) -> UsageReport {
+    let config = server_config();
+    UsageReport {
+        today: total_tokens(&claim.uuid, Period::Day).await,
+        this_month: total_tokens(&claim.uuid, Period::Month).await,
+        quota: resolve_quota(&config.quota, &claim.uuid, claim.role),
+        daily: DailyUsage::load(&claim.uuid, days).await,
+    }
+}
+
+async fn total_tokens(uuid: &str, period: Period) -> i64 {
+    TokenUsage::sum_since(uuid, period).await.total()
+}
+
+#[allow(unused)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quota_precedence() {
+        let user_quota = Quota {
+            daily_tokens: Some(10),
+            monthly_tokens: None,
+        };
+        let mut config = QuotaConfig::default();
+        config.users.insert("vip".into(), user_quota);
+        let role_quota = config.roles["user"];
+
+        assert_eq!(resolve_quota(&config, "vip", Role::User), user_quota);
+        assert_eq!(resolve_quota(&config, "other", Role::User), role_quota);
+        assert_eq!(
+            resolve_quota(&config, "other", Role::Admin),
+            Quota::default()
+        );
+    }
+}